@@ -0,0 +1,4 @@
+fn main() {
+    // The migrations are embedded into the binary, rebuild when one is added or changed
+    println!("cargo:rerun-if-changed=src/db/migrations");
+}
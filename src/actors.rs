@@ -1,2 +1,10 @@
+pub mod api_token;
 pub mod auth;
+pub mod identity;
+pub mod login_throttle;
+pub mod password_reset;
+pub mod refresh_token;
+pub mod session;
+pub mod two_factor;
 pub mod user;
+pub mod verification;
@@ -0,0 +1,137 @@
+use crate::errors::AppError;
+use crate::models::api_token_model::{ApiToken, CreateApiToken, API_TOKEN_PREFIX};
+use crate::utils::tokens::TokenSigner;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+/// Tokens a single user may hold at once
+const MAX_TOKENS_PER_USER: i64 = 50;
+
+/// `last_used_at` is only written when older than this, so busy scripts do not write on every call
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60;
+
+impl ApiToken {
+    /// Create a personal API token for a user
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `signer` - The signer used to derive the stored token hash
+    /// * `for_user` - The id of the user the token belongs to
+    /// * `data` - The name, scopes and lifetime of the token
+    ///
+    /// # Returns
+    ///
+    /// A tuple of the stored token and the plain token to show the user once; only its signature is stored
+    pub fn create(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        for_user: Uuid,
+        data: CreateApiToken,
+    ) -> Result<(ApiToken, String), AppError> {
+        use crate::schema::api_tokens::dsl::*;
+
+        let existing: i64 = api_tokens
+            .filter(user_id.eq(for_user))
+            .count()
+            .get_result(conn)?;
+        if existing >= MAX_TOKENS_PER_USER {
+            return Err(AppError::Conflict(format!(
+                "A user may have at most {} API tokens",
+                MAX_TOKENS_PER_USER
+            )));
+        }
+
+        let (secret, _) = signer.generate();
+        let token = format!("{}{}", API_TOKEN_PREFIX, secret);
+        let current_time = Utc::now().naive_utc();
+        let mut granted: Vec<String> = data
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str().to_string())
+            .collect();
+        granted.sort();
+        granted.dedup();
+
+        let created = diesel::insert_into(api_tokens)
+            .values(ApiToken {
+                id: Uuid::new_v4(),
+                user_id: for_user,
+                name: data.name,
+                token_hash: signer.sign(&token),
+                scopes: granted,
+                expires_at: data
+                    .expires_in_days
+                    .map(|days| current_time + Duration::days(days)),
+                last_used_at: None,
+                created_at: current_time,
+            })
+            .get_result::<ApiToken>(conn)?;
+
+        Ok((created, token))
+    }
+
+    /// Every token of a user, newest first, including expired ones
+    pub fn list(conn: &mut PgConnection, for_user: Uuid) -> Result<Vec<ApiToken>, AppError> {
+        use crate::schema::api_tokens::dsl::*;
+
+        let tokens = api_tokens
+            .filter(user_id.eq(for_user))
+            .order(created_at.desc())
+            .select(ApiToken::as_select())
+            .load(conn)?;
+
+        Ok(tokens)
+    }
+
+    /// Delete one of a user's tokens
+    ///
+    /// # Returns
+    ///
+    /// `false` if the user has no token with that id
+    pub fn revoke(
+        conn: &mut PgConnection,
+        for_user: Uuid,
+        token_id: Uuid,
+    ) -> Result<bool, AppError> {
+        use crate::schema::api_tokens::dsl::*;
+
+        let deleted = diesel::delete(api_tokens.find(token_id))
+            .filter(user_id.eq(for_user))
+            .execute(conn)?;
+
+        Ok(deleted == 1)
+    }
+
+    /// Look up the token presented in an `Authorization: Bearer` header and record its use
+    ///
+    /// # Returns
+    ///
+    /// The stored token, or `AppError::Unauthorized` if it is unknown or has expired
+    pub fn authenticate(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        token: &str,
+    ) -> Result<ApiToken, AppError> {
+        use crate::schema::api_tokens::dsl::*;
+
+        let current_time = Utc::now().naive_utc();
+        let found = api_tokens
+            .filter(token_hash.eq(signer.sign(token)))
+            .filter(expires_at.is_null().or(expires_at.gt(current_time)))
+            .select(ApiToken::as_select())
+            .first(conn)
+            .optional()?
+            .ok_or_else(|| AppError::Unauthorized("Invalid or expired API token".to_string()))?;
+
+        let stale_before = current_time - Duration::seconds(LAST_USED_RESOLUTION_SECONDS);
+        if found.last_used_at.is_none_or(|used| used < stale_before) {
+            diesel::update(api_tokens.find(found.id))
+                .set(last_used_at.eq(current_time))
+                .execute(conn)?;
+        }
+
+        Ok(found)
+    }
+}
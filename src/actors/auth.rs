@@ -1,37 +1,148 @@
 use actix_session::Session;
-use actix_web::HttpResponse;
-use bcrypt::verify;
+use actix_web::HttpRequest;
 
+use crate::actors::session::SessionIndex;
+use crate::errors::AppError;
 use crate::models::user_model::{AuthCredentials, User};
+use crate::utils::config::SessionConfig;
+use crate::utils::password::PasswordHasher;
+use crate::utils::redis::RedisAddr;
+use uuid::Uuid;
 
 pub struct Auth {}
 
 impl Auth {
+    /// Checks an email and password pair, taking about as long whether or not the account exists
+    ///
+    /// A password stored with an outdated algorithm or cost is rehashed with the current one
+    /// once it has been checked.
+    ///
+    /// # Returns
+    ///
+    /// The matching user, or `AppError::Unauthorized` without saying which of the two was wrong
     pub fn credentials(
-        conn: &mut diesel::r2d2::PooledConnection<
-            diesel::r2d2::ConnectionManager<diesel::PgConnection>,
-        >,
+        conn: &mut diesel::PgConnection,
+        hasher: &PasswordHasher,
         credentials: &AuthCredentials,
-    ) -> Result<String, HttpResponse> {
-        let user = User::find_user_by_email(conn, &credentials.email)
-            .map_err(|_e| HttpResponse::Unauthorized().json("Unauthorized"))?;
+        require_verified_email: bool,
+    ) -> Result<User, AppError> {
+        let user = match User::find_user_by_email(conn, &credentials.email) {
+            Ok(user) => user,
+            Err(AppError::NotFound(_)) => {
+                // Spend the same hashing work as for a real account so timing does not
+                // reveal which emails are registered
+                hasher.verify_dummy(&credentials.password)?;
+                return Err(AppError::Unauthorized(
+                    "Invalid email or password".to_string(),
+                ));
+            }
+            Err(err) => return Err(err),
+        };
+
+        if !Self::verify_password(hasher, &user, &credentials.password)? {
+            return Err(AppError::Unauthorized(
+                "Invalid email or password".to_string(),
+            ));
+        }
 
-        if verify(&credentials.password, &user.password).unwrap_or(false) {
-            Ok(user.email)
+        // The plain password is only ever known here, so this is the one chance to upgrade
+        // the hash; failing to do so must not fail the login
+        let user = if hasher.needs_rehash(&user.password) {
+            match User::update_password(conn, hasher, user.id, &credentials.password) {
+                Ok(updated_user) => updated_user,
+                Err(err) => {
+                    eprintln!("Failed to rehash password of user {}: {}", user.id, err);
+                    user
+                }
+            }
         } else {
-            Err(HttpResponse::Unauthorized().json("Unauthorized"))
+            user
+        };
+
+        if user.suspended_at.is_some() {
+            return Err(AppError::Forbidden("Account is suspended".to_string()));
         }
+
+        if require_verified_email && user.email_verified_at.is_none() {
+            return Err(AppError::EmailNotVerified);
+        }
+
+        Ok(user)
     }
 
-    pub fn validate_session(session: &Session) -> Result<String, HttpResponse> {
-        let user_email: Option<String> = session.get("user_email").unwrap_or(None);
+    /// Checks `password` against the stored hash of `user`
+    pub fn verify_password(
+        hasher: &PasswordHasher,
+        user: &User,
+        password: &str,
+    ) -> Result<bool, AppError> {
+        hasher.verify(password, &user.password)
+    }
 
-        match user_email {
-            Some(email) => {
+    /// Logs `user_id` in on this session and registers it in the user's session index
+    ///
+    /// `trust_proxy_headers` decides whether the recorded IP may come from forwarding headers.
+    pub async fn start_session(
+        session: &Session,
+        redis: &RedisAddr,
+        config: &SessionConfig,
+        req: &HttpRequest,
+        trust_proxy_headers: bool,
+        user_id: Uuid,
+    ) -> Result<(), AppError> {
+        let session_id =
+            SessionIndex::register(redis, config, user_id, req, trust_proxy_headers).await?;
+
+        // A fresh session key on login prevents session fixation
+        session.renew();
+        session
+            .insert("user_id", user_id)
+            .and_then(|_| session.insert("session_id", session_id))
+            .map_err(|err| AppError::Internal(format!("Failed to write session: {}", err)))
+    }
+
+    /// Id of the current session in the user's session index
+    pub fn current_session_id(session: &Session) -> Option<String> {
+        session.get("session_id").unwrap_or(None)
+    }
+
+    /// Logs out the current session, removing it from the user's session index
+    pub async fn end_session(session: &Session, redis: &RedisAddr) -> Result<(), AppError> {
+        let user_id: Option<Uuid> = session.get("user_id").unwrap_or(None);
+        let session_id = Self::current_session_id(session);
+
+        if let (Some(user_id), Some(id)) = (user_id, session_id) {
+            SessionIndex::revoke(redis, user_id, &id).await?;
+        }
+        session.purge();
+
+        Ok(())
+    }
+
+    /// Checks that the session is logged in and has not been revoked
+    ///
+    /// # Returns
+    ///
+    /// The id of the logged in user, or `AppError::Unauthorized` after purging the session
+    pub async fn validate_session(
+        session: &Session,
+        redis: &RedisAddr,
+        config: &SessionConfig,
+    ) -> Result<Uuid, AppError> {
+        let user_id: Option<Uuid> = session.get("user_id").unwrap_or(None);
+        let session_id = Self::current_session_id(session);
+
+        match (user_id, session_id) {
+            (Some(user_id), Some(id))
+                if SessionIndex::touch(redis, config, user_id, &id).await? =>
+            {
                 session.renew();
-                Ok(email)
+                Ok(user_id)
+            }
+            _ => {
+                session.purge();
+                Err(AppError::Unauthorized("Unauthorized".to_string()))
             }
-            None => Err(HttpResponse::Unauthorized().json("Unauthorized")),
         }
     }
 }
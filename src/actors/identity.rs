@@ -0,0 +1,189 @@
+use actix_redis::resp_array;
+use chrono::Utc;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::identity_model::{ExternalIdentity, OidcAuthorization, OidcUserInfo};
+use crate::utils::oidc::random_token;
+use crate::utils::redis::{bulk_string, execute, integer, RedisAddr};
+
+impl ExternalIdentity {
+    /// Find the identity a provider account is linked through
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `provider_name` - The name of the provider in `[oidc.providers]`
+    /// * `token_issuer` - The `iss` claim of the verified ID token
+    /// * `provider_subject` - The provider's id of the account
+    ///
+    /// # Returns
+    ///
+    /// The linked identity, or `None` if the account has never been linked
+    pub fn find(
+        conn: &mut PgConnection,
+        provider_name: &str,
+        token_issuer: &str,
+        provider_subject: &str,
+    ) -> Result<Option<ExternalIdentity>, AppError> {
+        use crate::schema::user_identities::dsl::*;
+
+        // Identities linked before issuers were recorded only know their provider name
+        let identity = user_identities
+            .filter(subject.eq(provider_subject))
+            .filter(
+                issuer
+                    .eq(token_issuer)
+                    .or(issuer.is_null().and(provider.eq(provider_name))),
+            )
+            .select(ExternalIdentity::as_select())
+            .first(conn)
+            .optional()?;
+
+        Ok(identity)
+    }
+
+    /// Link a provider account to a user
+    ///
+    /// # Returns
+    ///
+    /// The new identity, or `AppError::Conflict` if the provider account is linked to someone
+    /// else or the user already linked another account of the same provider
+    pub fn link(
+        conn: &mut PgConnection,
+        for_user: Uuid,
+        provider_name: &str,
+        token_issuer: &str,
+        info: &OidcUserInfo,
+    ) -> Result<ExternalIdentity, AppError> {
+        use crate::schema::user_identities::dsl::*;
+
+        let current_time = Utc::now().naive_utc();
+        let identity = diesel::insert_into(user_identities)
+            .values(ExternalIdentity {
+                id: Uuid::new_v4(),
+                user_id: for_user,
+                provider: provider_name.to_string(),
+                subject: info.sub.clone(),
+                email: info.email.clone(),
+                created_at: current_time,
+                last_login_at: Some(current_time),
+                issuer: Some(token_issuer.to_string()),
+            })
+            .get_result::<ExternalIdentity>(conn)?;
+
+        Ok(identity)
+    }
+
+    /// Record a sign in through the identity, refreshing the email the provider reported
+    /// and filling in the issuer of identities linked before it was recorded
+    pub fn record_login(
+        &self,
+        conn: &mut PgConnection,
+        token_issuer: &str,
+        info: &OidcUserInfo,
+    ) -> Result<(), AppError> {
+        use crate::schema::user_identities::dsl::*;
+
+        diesel::update(user_identities.find(self.id))
+            .set((
+                last_login_at.eq(Utc::now().naive_utc()),
+                email.eq(&info.email),
+                issuer.eq(token_issuer),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Every identity linked to a user, oldest first
+    pub fn list(
+        conn: &mut PgConnection,
+        for_user: Uuid,
+    ) -> Result<Vec<ExternalIdentity>, AppError> {
+        use crate::schema::user_identities::dsl::*;
+
+        let identities = user_identities
+            .filter(user_id.eq(for_user))
+            .order(created_at.asc())
+            .select(ExternalIdentity::as_select())
+            .load(conn)?;
+
+        Ok(identities)
+    }
+
+    /// Remove the link between a user and a provider
+    ///
+    /// # Returns
+    ///
+    /// `false` if the user has no identity at that provider
+    pub fn unlink(
+        conn: &mut PgConnection,
+        for_user: Uuid,
+        provider_name: &str,
+    ) -> Result<bool, AppError> {
+        use crate::schema::user_identities::dsl::*;
+
+        let deleted = diesel::delete(
+            user_identities
+                .filter(user_id.eq(for_user))
+                .filter(provider.eq(provider_name)),
+        )
+        .execute(conn)?;
+
+        Ok(deleted == 1)
+    }
+}
+
+impl OidcAuthorization {
+    fn key(state: &str) -> String {
+        format!("oidc_state:{}", state)
+    }
+
+    /// Remember the sign in until the provider redirects back
+    ///
+    /// # Returns
+    ///
+    /// The `state` to send to the provider
+    pub async fn store(&self, redis: &RedisAddr, ttl_seconds: i64) -> Result<String, AppError> {
+        let state = random_token();
+        let value = serde_json::to_string(self)
+            .map_err(|err| AppError::Internal(format!("Failed to encode OIDC state: {}", err)))?;
+
+        execute(
+            redis,
+            resp_array![
+                "SET",
+                Self::key(&state),
+                value,
+                "EX",
+                ttl_seconds.to_string()
+            ],
+        )
+        .await?;
+
+        Ok(state)
+    }
+
+    /// Load and forget the sign in a `state` was issued for, so a callback cannot be replayed
+    ///
+    /// # Returns
+    ///
+    /// The sign in, or `AppError::InvalidToken` if the state is unknown, used or expired
+    pub async fn take(redis: &RedisAddr, state: &str) -> Result<OidcAuthorization, AppError> {
+        let key = Self::key(state);
+        let invalid = || AppError::InvalidToken("Invalid or expired sign in state".to_string());
+
+        let value = execute(redis, resp_array!["GET", &key]).await?;
+        // Only the request that actually deletes the state may use it
+        let deleted = execute(redis, resp_array!["DEL", &key]).await?;
+        if integer(&deleted) != Some(1) {
+            return Err(invalid());
+        }
+
+        bulk_string(value)
+            .and_then(|value| serde_json::from_str(&value).ok())
+            .ok_or_else(invalid)
+    }
+}
@@ -0,0 +1,235 @@
+use actix_redis::{resp_array, RespValue};
+use chrono::{DateTime, Utc};
+
+use crate::errors::AppError;
+use crate::models::lockout_model::{Lockout, LockoutKind};
+use crate::utils::config::LoginThrottleConfig;
+use crate::utils::redis::{bulk_string, execute, integer, RedisAddr};
+
+/// Sorted set of every active lockout, scored by the unix time it ends, so admins can list them
+const LOCKOUT_INDEX_KEY: &str = "login_lockouts";
+
+/// How long repeated lockouts keep growing before the backoff starts over
+const LOCKOUT_LEVEL_TTL_SECONDS: i64 = 24 * 60 * 60;
+
+/// Counts failed logins per account and per client IP in Redis and locks them out
+/// with exponential backoff once `LoginThrottleConfig` limits are reached
+pub struct LoginThrottle {}
+
+impl LoginThrottle {
+    fn failures_key(kind: LockoutKind, subject: &str) -> String {
+        format!("login_failures:{}:{}", kind.as_str(), subject)
+    }
+
+    fn lockout_key(kind: LockoutKind, subject: &str) -> String {
+        format!("login_lockout:{}:{}", kind.as_str(), subject)
+    }
+
+    fn level_key(kind: LockoutKind, subject: &str) -> String {
+        format!("login_lockout_level:{}:{}", kind.as_str(), subject)
+    }
+
+    fn index_member(kind: LockoutKind, subject: &str) -> String {
+        format!("{}:{}", kind.as_str(), subject)
+    }
+
+    /// Fails with `AppError::TooManyRequests` if the account or the IP is locked out
+    pub async fn check(
+        redis: &RedisAddr,
+        config: &LoginThrottleConfig,
+        ip: &str,
+        email: &str,
+    ) -> Result<(), AppError> {
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let mut retry_after = 0;
+        for (kind, subject) in [(LockoutKind::Account, email), (LockoutKind::Ip, ip)] {
+            let ttl = execute(redis, resp_array!["TTL", Self::lockout_key(kind, subject)]).await?;
+            retry_after = retry_after.max(integer(&ttl).unwrap_or(0));
+        }
+
+        if retry_after > 0 {
+            Err(AppError::TooManyRequests {
+                retry_after: retry_after as u64,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Counts a failed login against the account and the IP, locking out whichever hit its limit
+    pub async fn record_failure(
+        redis: &RedisAddr,
+        config: &LoginThrottleConfig,
+        ip: &str,
+        email: &str,
+    ) -> Result<(), AppError> {
+        if !config.enabled {
+            return Ok(());
+        }
+
+        for (kind, subject, max_attempts) in [
+            (LockoutKind::Account, email, config.max_attempts_per_account),
+            (LockoutKind::Ip, ip, config.max_attempts_per_ip),
+        ] {
+            let failures_key = Self::failures_key(kind, subject);
+            let failures =
+                integer(&execute(redis, resp_array!["INCR", &failures_key]).await?).unwrap_or(0);
+            if failures == 1 {
+                execute(
+                    redis,
+                    resp_array!["EXPIRE", &failures_key, config.window_seconds.to_string()],
+                )
+                .await?;
+            }
+
+            if failures >= max_attempts {
+                Self::lock(redis, config, kind, subject).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the failed attempts of an account after it logged in successfully
+    ///
+    /// The IP counter is left alone, otherwise an attacker holding one valid account
+    /// could reset it between guesses against other accounts.
+    pub async fn record_success(redis: &RedisAddr, email: &str) -> Result<(), AppError> {
+        execute(
+            redis,
+            resp_array![
+                "DEL",
+                Self::failures_key(LockoutKind::Account, email),
+                Self::level_key(LockoutKind::Account, email)
+            ],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Every account and IP that is currently locked out, soonest to expire first
+    pub async fn lockouts(redis: &RedisAddr) -> Result<Vec<Lockout>, AppError> {
+        let now = Utc::now().timestamp().to_string();
+        execute(
+            redis,
+            resp_array!["ZREMRANGEBYSCORE", LOCKOUT_INDEX_KEY, "-inf", &now],
+        )
+        .await?;
+
+        let values = match execute(
+            redis,
+            resp_array!["ZRANGE", LOCKOUT_INDEX_KEY, "0", "-1", "WITHSCORES"],
+        )
+        .await?
+        {
+            RespValue::Array(values) => values,
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut lockouts = Vec::with_capacity(values.len() / 2);
+        let mut values = values.into_iter();
+        while let (Some(member), Some(score)) = (values.next(), values.next()) {
+            let (Some(member), Some(score)) = (bulk_string(member), bulk_string(score)) else {
+                continue;
+            };
+            let (kind, subject) = match member.split_once(':') {
+                Some(("account", subject)) => (LockoutKind::Account, subject),
+                Some(("ip", subject)) => (LockoutKind::Ip, subject),
+                _ => continue,
+            };
+            let locked_until = score
+                .parse::<f64>()
+                .ok()
+                .and_then(|seconds| DateTime::from_timestamp(seconds as i64, 0));
+
+            if let Some(locked_until) = locked_until {
+                lockouts.push(Lockout {
+                    kind,
+                    subject: subject.to_string(),
+                    locked_until: locked_until.naive_utc(),
+                });
+            }
+        }
+
+        Ok(lockouts)
+    }
+
+    /// Lifts a lockout and forgets the failed attempts that led to it
+    ///
+    /// # Returns
+    ///
+    /// `false` if the subject was not locked out
+    pub async fn clear(
+        redis: &RedisAddr,
+        kind: LockoutKind,
+        subject: &str,
+    ) -> Result<bool, AppError> {
+        let removed = execute(
+            redis,
+            resp_array![
+                "DEL",
+                Self::lockout_key(kind, subject),
+                Self::failures_key(kind, subject),
+                Self::level_key(kind, subject)
+            ],
+        )
+        .await?;
+        execute(
+            redis,
+            resp_array!["ZREM", LOCKOUT_INDEX_KEY, Self::index_member(kind, subject)],
+        )
+        .await?;
+
+        Ok(integer(&removed).unwrap_or(0) > 0)
+    }
+
+    async fn lock(
+        redis: &RedisAddr,
+        config: &LoginThrottleConfig,
+        kind: LockoutKind,
+        subject: &str,
+    ) -> Result<(), AppError> {
+        let level_key = Self::level_key(kind, subject);
+        let level = integer(&execute(redis, resp_array!["INCR", &level_key]).await?).unwrap_or(1);
+        execute(
+            redis,
+            resp_array!["EXPIRE", &level_key, LOCKOUT_LEVEL_TTL_SECONDS.to_string()],
+        )
+        .await?;
+
+        let duration = config
+            .lockout_seconds
+            .saturating_mul(1 << (level - 1).clamp(0, 20))
+            .min(config.max_lockout_seconds);
+        let locked_until = Utc::now().timestamp() + duration;
+
+        execute(
+            redis,
+            resp_array![
+                "SET",
+                Self::lockout_key(kind, subject),
+                "1",
+                "EX",
+                duration.to_string()
+            ],
+        )
+        .await?;
+        execute(redis, resp_array!["DEL", Self::failures_key(kind, subject)]).await?;
+        execute(
+            redis,
+            resp_array![
+                "ZADD",
+                LOCKOUT_INDEX_KEY,
+                locked_until.to_string(),
+                Self::index_member(kind, subject)
+            ],
+        )
+        .await?;
+
+        Ok(())
+    }
+}
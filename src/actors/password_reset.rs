@@ -0,0 +1,104 @@
+use crate::errors::AppError;
+use crate::models::password_reset_model::PasswordResetToken;
+use crate::models::user_model::User;
+use crate::utils::password::PasswordHasher;
+use crate::utils::tokens::TokenSigner;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+impl PasswordResetToken {
+    /// Issue a new password reset token for a user, invalidating any previous ones
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `signer` - The signer used to derive the stored token hash
+    /// * `for_user` - The id of the user the token belongs to
+    /// * `ttl` - How long the token stays valid
+    ///
+    /// # Returns
+    ///
+    /// The plain token to send to the user; only its signature is stored
+    pub fn issue(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        for_user: Uuid,
+        ttl: Duration,
+    ) -> Result<String, AppError> {
+        use crate::schema::password_reset_tokens::dsl::*;
+
+        let (token, signature) = signer.generate();
+        let current_time = Utc::now().naive_utc();
+
+        conn.transaction(|conn| {
+            diesel::update(password_reset_tokens.filter(user_id.eq(for_user)))
+                .filter(used_at.is_null())
+                .set(used_at.eq(current_time))
+                .execute(conn)?;
+
+            diesel::insert_into(password_reset_tokens)
+                .values(PasswordResetToken {
+                    id: Uuid::new_v4(),
+                    user_id: for_user,
+                    token_hash: signature,
+                    expires_at: current_time + ttl,
+                    used_at: None,
+                    created_at: current_time,
+                })
+                .execute(conn)
+        })?;
+
+        Ok(token)
+    }
+
+    /// Consume a password reset token and set the owning user's new password
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `signer` - The signer used to derive the stored token hash
+    /// * `hasher` - The password hasher, using the preferred algorithm
+    /// * `token` - The plain token received from the user
+    /// * `new_password` - The new plain text password
+    ///
+    /// # Returns
+    ///
+    /// The updated `User`, or `AppError::InvalidToken` if the token is unknown, used or expired
+    pub fn consume(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        hasher: &PasswordHasher,
+        token: &str,
+        new_password: &str,
+    ) -> Result<User, AppError> {
+        use crate::schema::password_reset_tokens::dsl::*;
+
+        let signature = signer.sign(token);
+        let current_time = Utc::now().naive_utc();
+
+        conn.transaction(|conn| {
+            let reset = password_reset_tokens
+                .filter(token_hash.eq(&signature))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(current_time))
+                .select(PasswordResetToken::as_select())
+                .first(conn)
+                .optional()?
+                .ok_or_else(|| AppError::InvalidToken("Invalid or expired token".to_string()))?;
+
+            // Conditional update so two requests racing with the same token cannot both use it
+            let updated = diesel::update(password_reset_tokens.find(reset.id))
+                .filter(used_at.is_null())
+                .set(used_at.eq(current_time))
+                .execute(conn)?;
+            if updated == 0 {
+                return Err(AppError::InvalidToken(
+                    "Invalid or expired token".to_string(),
+                ));
+            }
+
+            User::update_password(conn, hasher, reset.user_id, new_password)
+        })
+    }
+}
@@ -0,0 +1,229 @@
+use actix_redis::resp_array;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::refresh_token_model::RefreshToken;
+use crate::utils::config::{with_database_connection, JwtConfig};
+use crate::utils::helpers::DbPool;
+use crate::utils::redis::{execute, integer, RedisAddr};
+use crate::utils::tokens::TokenSigner;
+
+impl RefreshToken {
+    /// Redis key marking a family as revoked for as long as its access tokens can live
+    fn revoked_family_key(family: Uuid) -> String {
+        format!("revoked_token_family:{}", family)
+    }
+
+    /// Issue a refresh token for a user, starting a new family unless `family` is given
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `signer` - The signer used to derive the stored token hash
+    /// * `config` - The JWT settings, for the refresh token lifetime
+    /// * `for_user` - The id of the user the token belongs to
+    /// * `family` - The family of the token being rotated, `None` on login
+    ///
+    /// # Returns
+    ///
+    /// A tuple of the family id and the plain token; only its signature is stored
+    pub fn issue(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        config: &JwtConfig,
+        for_user: Uuid,
+        family: Option<Uuid>,
+    ) -> Result<(Uuid, String), AppError> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        let (token, signature) = signer.generate();
+        let family = family.unwrap_or_else(Uuid::new_v4);
+        let current_time = Utc::now().naive_utc();
+
+        diesel::insert_into(refresh_tokens)
+            .values(RefreshToken {
+                id: Uuid::new_v4(),
+                user_id: for_user,
+                family_id: family,
+                token_hash: signature,
+                expires_at: current_time + Duration::days(config.refresh_token_ttl_days),
+                used_at: None,
+                revoked_at: None,
+                created_at: current_time,
+            })
+            .execute(conn)?;
+
+        Ok((family, token))
+    }
+
+    /// Exchange a refresh token for a new one of the same family
+    ///
+    /// A token that was already rotated is evidence of theft: its whole family is revoked,
+    /// logging out both the attacker and the legitimate client.
+    ///
+    /// # Returns
+    ///
+    /// A tuple of the user id, the family id and the new plain token,
+    /// or `AppError::InvalidToken` if the token is unknown, expired, revoked or reused
+    pub async fn rotate(
+        pool: &DbPool,
+        redis: &RedisAddr,
+        signer: &TokenSigner,
+        config: &JwtConfig,
+        token: &str,
+    ) -> Result<(Uuid, Uuid, String), AppError> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        let invalid = || AppError::InvalidToken("Invalid or expired refresh token".to_string());
+        let hash = signer.sign(token);
+        let (signer, jwt_config) = (signer.clone(), config.clone());
+
+        let (stored, rotated) = with_database_connection(pool, move |conn| {
+            let current_time = Utc::now().naive_utc();
+            let stored = refresh_tokens
+                .filter(token_hash.eq(hash))
+                .select(RefreshToken::as_select())
+                .first(conn)
+                .optional()?
+                .ok_or_else(invalid)?;
+
+            if stored.revoked_at.is_some() || stored.expires_at <= current_time {
+                return Err(invalid());
+            }
+
+            let rotated = conn.transaction(|conn| {
+                // Conditional update so two requests racing with the same token cannot both rotate it
+                let updated = diesel::update(refresh_tokens.find(stored.id))
+                    .filter(used_at.is_null())
+                    .set(used_at.eq(current_time))
+                    .execute(conn)?;
+                if updated == 0 {
+                    return Ok(None);
+                }
+
+                Self::issue(
+                    conn,
+                    &signer,
+                    &jwt_config,
+                    stored.user_id,
+                    Some(stored.family_id),
+                )
+                .map(Some)
+            })?;
+
+            Ok((stored, rotated))
+        })
+        .await?;
+
+        match rotated {
+            Some((family, new_token)) => Ok((stored.user_id, family, new_token)),
+            None => {
+                eprintln!(
+                    "Refresh token reuse detected for user {}, revoking family {}",
+                    stored.user_id, stored.family_id
+                );
+                Self::revoke_family(pool, redis, config, stored.family_id).await?;
+                Err(invalid())
+            }
+        }
+    }
+
+    /// Revoke the family of a refresh token, doing nothing if the token is unknown
+    pub async fn revoke(
+        pool: &DbPool,
+        redis: &RedisAddr,
+        signer: &TokenSigner,
+        config: &JwtConfig,
+        token: &str,
+    ) -> Result<(), AppError> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        let hash = signer.sign(token);
+        let family = with_database_connection(pool, move |conn| {
+            Ok(refresh_tokens
+                .filter(token_hash.eq(hash))
+                .select(family_id)
+                .first::<Uuid>(conn)
+                .optional()?)
+        })
+        .await?;
+
+        match family {
+            Some(family) => Self::revoke_family(pool, redis, config, family).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Revoke every refresh token of a family and the access tokens issued from it
+    pub async fn revoke_family(
+        pool: &DbPool,
+        redis: &RedisAddr,
+        config: &JwtConfig,
+        family: Uuid,
+    ) -> Result<(), AppError> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        with_database_connection(pool, move |conn| {
+            diesel::update(refresh_tokens.filter(family_id.eq(family)))
+                .filter(revoked_at.is_null())
+                .set(revoked_at.eq(Utc::now().naive_utc()))
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .await?;
+
+        execute(
+            redis,
+            resp_array![
+                "SET",
+                Self::revoked_family_key(family),
+                "1",
+                "EX",
+                config.access_token_ttl_seconds.max(1).to_string()
+            ],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revoke every token family of a user, e.g. after a password change
+    pub async fn revoke_all(
+        pool: &DbPool,
+        redis: &RedisAddr,
+        config: &JwtConfig,
+        for_user: Uuid,
+    ) -> Result<(), AppError> {
+        use crate::schema::refresh_tokens::dsl::*;
+
+        let families = with_database_connection(pool, move |conn| {
+            Ok(refresh_tokens
+                .filter(user_id.eq(for_user))
+                .filter(revoked_at.is_null())
+                .select(family_id)
+                .distinct()
+                .load::<Uuid>(conn)?)
+        })
+        .await?;
+
+        for family in families {
+            Self::revoke_family(pool, redis, config, family).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether access tokens issued from `family` have been revoked
+    pub async fn is_family_revoked(redis: &RedisAddr, family: Uuid) -> Result<bool, AppError> {
+        let exists = execute(
+            redis,
+            resp_array!["EXISTS", Self::revoked_family_key(family)],
+        )
+        .await?;
+
+        Ok(integer(&exists) == Some(1))
+    }
+}
@@ -0,0 +1,217 @@
+use actix_redis::{resp_array, RespValue};
+use actix_web::HttpRequest;
+use chrono::{Duration, NaiveDateTime, Utc};
+use std::cmp::Reverse;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::session_model::SessionInfo;
+use crate::utils::config::SessionConfig;
+use crate::utils::helpers::client_ip;
+use crate::utils::redis::{bulk_string, execute, RedisAddr};
+
+/// Per-user index of live sessions, stored in Redis next to the session state
+///
+/// Each login gets its own id in the `user_sessions:{user_id}` hash, mapped to its
+/// `SessionInfo`. A session whose id is no longer in the hash is rejected by
+/// `Auth::validate_session`, which is how sessions on other devices are invalidated
+/// without knowing their cookies. Last-seen timestamps live in a separate
+/// `user_sessions_seen:{user_id}` hash so that recording activity can never bring
+/// a revoked session back.
+///
+/// The hashes only expire as a whole, so entries unused for longer than the session
+/// lifetime are pruned whenever the index is read or a session is added.
+pub struct SessionIndex {}
+
+impl SessionIndex {
+    fn key(user_id: Uuid) -> String {
+        format!("user_sessions:{}", user_id)
+    }
+
+    fn seen_key(user_id: Uuid) -> String {
+        format!("user_sessions_seen:{}", user_id)
+    }
+
+    /// Registers a new session for a user, recording the device it was created from
+    ///
+    /// The IP is only taken from forwarding headers when `trust_proxy_headers` is set.
+    ///
+    /// # Returns
+    ///
+    /// The id of the new session, to be stored in the session itself
+    pub async fn register(
+        redis: &RedisAddr,
+        config: &SessionConfig,
+        user_id: Uuid,
+        req: &HttpRequest,
+        trust_proxy_headers: bool,
+    ) -> Result<String, AppError> {
+        Self::live_sessions(redis, config, user_id).await?;
+
+        let current_time = Utc::now().naive_utc();
+        let info = SessionInfo {
+            id: Uuid::new_v4().to_string(),
+            user_agent: req
+                .headers()
+                .get("User-Agent")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            ip: Some(client_ip(req, trust_proxy_headers)),
+            created_at: current_time,
+            last_seen_at: current_time,
+        };
+        let serialized = serde_json::to_string(&info)
+            .map_err(|err| AppError::Internal(format!("Failed to serialize session: {}", err)))?;
+
+        execute(
+            redis,
+            resp_array!["HSET", Self::key(user_id), &info.id, serialized],
+        )
+        .await?;
+        Self::extend(redis, config, user_id).await?;
+
+        Ok(info.id)
+    }
+
+    /// Records activity on a session
+    ///
+    /// # Returns
+    ///
+    /// `false` if `session_id` is no longer a live session of the user
+    pub async fn touch(
+        redis: &RedisAddr,
+        config: &SessionConfig,
+        user_id: Uuid,
+        session_id: &str,
+    ) -> Result<bool, AppError> {
+        let exists = execute(
+            redis,
+            resp_array!["HEXISTS", Self::key(user_id), session_id],
+        )
+        .await?;
+        if !matches!(exists, RespValue::Integer(1)) {
+            return Ok(false);
+        }
+
+        let now = Utc::now()
+            .naive_utc()
+            .format("%Y-%m-%dT%H:%M:%S%.f")
+            .to_string();
+        execute(
+            redis,
+            resp_array!["HSET", Self::seen_key(user_id), session_id, now],
+        )
+        .await?;
+        Self::extend(redis, config, user_id).await?;
+
+        Ok(true)
+    }
+
+    /// Lists the live sessions of the user, most recently used first
+    pub async fn list(
+        redis: &RedisAddr,
+        config: &SessionConfig,
+        user_id: Uuid,
+    ) -> Result<Vec<SessionInfo>, AppError> {
+        let mut infos = Self::live_sessions(redis, config, user_id).await?;
+        infos.sort_by_key(|info| Reverse(info.last_seen_at));
+
+        Ok(infos)
+    }
+
+    /// Sessions of the user used within the session lifetime, removing the others
+    /// from the index since the session store has already forgotten them
+    async fn live_sessions(
+        redis: &RedisAddr,
+        config: &SessionConfig,
+        user_id: Uuid,
+    ) -> Result<Vec<SessionInfo>, AppError> {
+        let sessions = Self::hash_entries(redis, &Self::key(user_id)).await?;
+        let last_seen = Self::hash_entries(redis, &Self::seen_key(user_id)).await?;
+        let expired_before = Utc::now().naive_utc() - Duration::days(config.ttl_days);
+
+        let mut infos = Vec::with_capacity(sessions.len());
+        for (id, value) in sessions {
+            let Ok(mut info) = serde_json::from_str::<SessionInfo>(&value) else {
+                continue;
+            };
+            if let Some(seen) = last_seen
+                .iter()
+                .find(|(seen_id, _)| *seen_id == id)
+                .and_then(|(_, seen)| seen.parse::<NaiveDateTime>().ok())
+            {
+                info.last_seen_at = seen;
+            }
+
+            if info.last_seen_at < expired_before {
+                Self::revoke(redis, user_id, &id).await?;
+            } else {
+                infos.push(info);
+            }
+        }
+
+        Ok(infos)
+    }
+
+    /// Invalidates a single session of the user
+    ///
+    /// # Returns
+    ///
+    /// `false` if the user had no such session
+    pub async fn revoke(
+        redis: &RedisAddr,
+        user_id: Uuid,
+        session_id: &str,
+    ) -> Result<bool, AppError> {
+        let removed = execute(redis, resp_array!["HDEL", Self::key(user_id), session_id]).await?;
+        execute(
+            redis,
+            resp_array!["HDEL", Self::seen_key(user_id), session_id],
+        )
+        .await?;
+
+        Ok(matches!(removed, RespValue::Integer(n) if n > 0))
+    }
+
+    /// Invalidates every session of the user
+    pub async fn revoke_all(redis: &RedisAddr, user_id: Uuid) -> Result<(), AppError> {
+        execute(
+            redis,
+            resp_array!["DEL", Self::key(user_id), Self::seen_key(user_id)],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Keeps the index alive for as long as the most recently used session
+    async fn extend(
+        redis: &RedisAddr,
+        config: &SessionConfig,
+        user_id: Uuid,
+    ) -> Result<(), AppError> {
+        let ttl_seconds = (config.ttl_days * 24 * 60 * 60).to_string();
+        for key in [Self::key(user_id), Self::seen_key(user_id)] {
+            execute(redis, resp_array!["EXPIRE", key, &ttl_seconds]).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn hash_entries(redis: &RedisAddr, key: &str) -> Result<Vec<(String, String)>, AppError> {
+        let values = match execute(redis, resp_array!["HGETALL", key]).await? {
+            RespValue::Array(values) => values,
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut entries = Vec::with_capacity(values.len() / 2);
+        let mut values = values.into_iter();
+        while let (Some(field), Some(value)) = (values.next(), values.next()) {
+            if let (Some(field), Some(value)) = (bulk_string(field), bulk_string(value)) {
+                entries.push((field, value));
+            }
+        }
+
+        Ok(entries)
+    }
+}
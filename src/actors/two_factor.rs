@@ -0,0 +1,478 @@
+use actix_redis::resp_array;
+use chrono::Utc;
+use diesel::prelude::*;
+use rand::{Rng, RngCore};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::two_factor_model::RecoveryCode;
+use crate::models::user_model::{User, UserChanges};
+use crate::utils::config::TwoFactorConfig;
+use crate::utils::redis::{bulk_string, execute, integer, RedisAddr};
+use crate::utils::tokens::TokenSigner;
+
+/// Seconds each TOTP code is valid for, the value every authenticator app assumes
+const TOTP_STEP_SECONDS: u64 = 30;
+
+const TOTP_DIGITS: usize = 6;
+
+/// Characters recovery codes are made of, without look-alikes such as `0`/`o` and `1`/`l`
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+/// Length of a recovery code, shown to the user split in two halves
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// TOTP two-factor authentication: enrollment, code checks, recovery codes and
+/// the pending login tokens handed out between the password and the code step
+pub struct TwoFactor {}
+
+impl TwoFactor {
+    fn login_token_key(signature: &str) -> String {
+        format!("login_2fa:{}", signature)
+    }
+
+    fn totp(config: &TwoFactorConfig, secret: &str, account: &str) -> Result<TOTP, AppError> {
+        let secret = Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .map_err(|err| AppError::Internal(format!("Invalid TOTP secret: {:?}", err)))?;
+
+        // The skew is handled by `verify_totp` so it knows which step matched
+        TOTP::new(
+            Algorithm::SHA1,
+            TOTP_DIGITS,
+            0,
+            TOTP_STEP_SECONDS,
+            secret,
+            Some(config.issuer.clone()),
+            account.to_string(),
+        )
+        .map_err(|err| AppError::Internal(format!("Invalid TOTP parameters: {:?}", err)))
+    }
+
+    /// Start enrolling `user`, replacing any enrollment that was never confirmed
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `config` - The 2FA settings, for the issuer name
+    /// * `user` - The user enrolling
+    ///
+    /// # Returns
+    ///
+    /// A tuple of the base32 secret and the `otpauth://` URI to show as a QR code
+    pub fn begin_setup(
+        conn: &mut PgConnection,
+        config: &TwoFactorConfig,
+        user: &User,
+    ) -> Result<(String, String), AppError> {
+        if user.two_factor_enabled() {
+            return Err(AppError::Conflict(
+                "Two-factor authentication is already enabled".to_string(),
+            ));
+        }
+
+        let mut secret = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let secret = match Secret::Raw(secret.to_vec()).to_encoded() {
+            Secret::Encoded(secret) => secret,
+            Secret::Raw(_) => unreachable!("to_encoded always returns an encoded secret"),
+        };
+        let totp = Self::totp(config, &secret, &user.email)?;
+
+        User::update_user(
+            conn,
+            user.id,
+            &UserChanges {
+                totp_secret: Some(Some(secret.clone())),
+                totp_enabled_at: Some(None),
+                totp_last_used_step: Some(None),
+                ..Default::default()
+            },
+        )?;
+
+        Ok((secret, totp.get_url()))
+    }
+
+    /// Finish enrolling `user` once they prove their app produces valid codes
+    ///
+    /// # Returns
+    ///
+    /// The plain recovery codes, which are only ever shown this once
+    pub fn confirm(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        config: &TwoFactorConfig,
+        user: &User,
+        code: &str,
+    ) -> Result<Vec<String>, AppError> {
+        if user.two_factor_enabled() {
+            return Err(AppError::Conflict(
+                "Two-factor authentication is already enabled".to_string(),
+            ));
+        }
+        if user.totp_secret.is_none() {
+            return Err(AppError::BadRequest(
+                "Two-factor authentication setup has not been started".to_string(),
+            ));
+        }
+        if !Self::verify_totp(conn, config, user, code)? {
+            return Err(AppError::InvalidToken("Invalid code".to_string()));
+        }
+
+        conn.transaction(|conn| {
+            User::update_user(
+                conn,
+                user.id,
+                &UserChanges {
+                    totp_enabled_at: Some(Some(Utc::now().naive_utc())),
+                    ..Default::default()
+                },
+            )?;
+
+            Self::issue_recovery_codes(conn, signer, config, user.id)
+        })
+    }
+
+    /// Turn 2FA off for `for_user`, dropping the secret and every recovery code
+    pub fn disable(conn: &mut PgConnection, for_user: Uuid) -> Result<(), AppError> {
+        use crate::schema::two_factor_recovery_codes::dsl::*;
+
+        conn.transaction(|conn| {
+            diesel::delete(two_factor_recovery_codes.filter(user_id.eq(for_user))).execute(conn)?;
+
+            User::update_user(
+                conn,
+                for_user,
+                &UserChanges {
+                    totp_secret: Some(None),
+                    totp_enabled_at: Some(None),
+                    totp_last_used_step: Some(None),
+                    ..Default::default()
+                },
+            )?;
+
+            Ok(())
+        })
+    }
+
+    /// Replace every recovery code of `for_user` with a fresh set
+    ///
+    /// # Returns
+    ///
+    /// The plain recovery codes; only their signatures are stored
+    pub fn issue_recovery_codes(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        config: &TwoFactorConfig,
+        for_user: Uuid,
+    ) -> Result<Vec<String>, AppError> {
+        use crate::schema::two_factor_recovery_codes::dsl::*;
+
+        let mut rng = rand::thread_rng();
+        let codes: Vec<String> = (0..config.recovery_code_count)
+            .map(|_| {
+                (0..RECOVERY_CODE_LENGTH)
+                    .map(|_| {
+                        RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())]
+                            as char
+                    })
+                    .collect()
+            })
+            .collect();
+        let current_time = Utc::now().naive_utc();
+        let rows: Vec<RecoveryCode> = codes
+            .iter()
+            .map(|code| RecoveryCode {
+                id: Uuid::new_v4(),
+                user_id: for_user,
+                code_hash: signer.sign(code),
+                used_at: None,
+                created_at: current_time,
+            })
+            .collect();
+
+        conn.transaction(|conn| {
+            diesel::delete(two_factor_recovery_codes.filter(user_id.eq(for_user))).execute(conn)?;
+            diesel::insert_into(two_factor_recovery_codes)
+                .values(&rows)
+                .execute(conn)
+        })?;
+
+        Ok(codes
+            .into_iter()
+            .map(|code| {
+                let (first, second) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+                format!("{}-{}", first, second)
+            })
+            .collect())
+    }
+
+    /// Check a second factor for `user`, either a TOTP code or an unused recovery code
+    ///
+    /// Accepted codes are used up: a TOTP code cannot be replayed and a recovery code
+    /// is marked as used.
+    pub fn verify(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        config: &TwoFactorConfig,
+        user: &User,
+        code: &str,
+    ) -> Result<bool, AppError> {
+        if !user.two_factor_enabled() {
+            return Ok(false);
+        }
+
+        let code: String = code
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .collect::<String>()
+            .to_lowercase();
+        if code.len() == TOTP_DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+            Self::verify_totp(conn, config, user, &code)
+        } else {
+            Self::use_recovery_code(conn, signer, user.id, &code)
+        }
+    }
+
+    /// Check a TOTP code against the current time step and its neighbours, allowing
+    /// for clock drift, and record the matching step so the code cannot be used again
+    fn verify_totp(
+        conn: &mut PgConnection,
+        config: &TwoFactorConfig,
+        user: &User,
+        code: &str,
+    ) -> Result<bool, AppError> {
+        let current_step = Utc::now().timestamp() as u64 / TOTP_STEP_SECONDS;
+
+        Self::verify_totp_at(conn, config, user, code, current_step)
+    }
+
+    fn verify_totp_at(
+        conn: &mut PgConnection,
+        config: &TwoFactorConfig,
+        user: &User,
+        code: &str,
+        current_step: u64,
+    ) -> Result<bool, AppError> {
+        use crate::schema::users::dsl::*;
+
+        let Some(secret) = &user.totp_secret else {
+            return Ok(false);
+        };
+        let totp = Self::totp(config, secret, &user.email)?;
+        let Some(step) = Self::matching_step(&totp, code, current_step) else {
+            return Ok(false);
+        };
+
+        // Conditional update so two requests racing with the same code cannot both succeed
+        let updated = diesel::update(users.find(user.id))
+            .filter(
+                totp_last_used_step
+                    .is_null()
+                    .or(totp_last_used_step.lt(step as i64)),
+            )
+            .set(totp_last_used_step.eq(step as i64))
+            .execute(conn)?;
+
+        Ok(updated == 1)
+    }
+
+    /// The step `code` belongs to, out of `current_step` and its two neighbours
+    fn matching_step(totp: &TOTP, code: &str, current_step: u64) -> Option<u64> {
+        (current_step - 1..=current_step + 1)
+            .find(|step| totp.check(code, step * TOTP_STEP_SECONDS))
+    }
+
+    fn use_recovery_code(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        for_user: Uuid,
+        code: &str,
+    ) -> Result<bool, AppError> {
+        use crate::schema::two_factor_recovery_codes::dsl::*;
+
+        let updated = diesel::update(two_factor_recovery_codes)
+            .filter(user_id.eq(for_user))
+            .filter(code_hash.eq(signer.sign(code)))
+            .filter(used_at.is_null())
+            .set(used_at.eq(Utc::now().naive_utc()))
+            .execute(conn)?;
+
+        Ok(updated == 1)
+    }
+
+    /// Hand out a pending login token for `user_id` once their password has been checked
+    ///
+    /// # Returns
+    ///
+    /// The plain token to send back with the 2FA code; only its signature is stored
+    pub async fn issue_login_token(
+        redis: &RedisAddr,
+        signer: &TokenSigner,
+        config: &TwoFactorConfig,
+        user_id: Uuid,
+    ) -> Result<String, AppError> {
+        let (token, signature) = signer.generate();
+        execute(
+            redis,
+            resp_array![
+                "SET",
+                Self::login_token_key(&signature),
+                user_id.to_string(),
+                "EX",
+                config.login_token_ttl_seconds.to_string()
+            ],
+        )
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Id of the user a pending login token was issued to
+    ///
+    /// # Returns
+    ///
+    /// The user id, or `AppError::InvalidToken` if the token is unknown or has expired
+    pub async fn login_token_user(
+        redis: &RedisAddr,
+        signer: &TokenSigner,
+        token: &str,
+    ) -> Result<Uuid, AppError> {
+        let value = execute(
+            redis,
+            resp_array!["GET", Self::login_token_key(&signer.sign(token))],
+        )
+        .await?;
+
+        bulk_string(value)
+            .and_then(|user_id| Uuid::parse_str(&user_id).ok())
+            .ok_or_else(|| AppError::InvalidToken("Invalid or expired login token".to_string()))
+    }
+
+    /// Use up a pending login token
+    ///
+    /// # Returns
+    ///
+    /// `AppError::InvalidToken` if the token was already used, e.g. by a concurrent request
+    pub async fn consume_login_token(
+        redis: &RedisAddr,
+        signer: &TokenSigner,
+        token: &str,
+    ) -> Result<(), AppError> {
+        let deleted = execute(
+            redis,
+            resp_array!["DEL", Self::login_token_key(&signer.sign(token))],
+        )
+        .await?;
+
+        if integer(&deleted) == Some(1) {
+            Ok(())
+        } else {
+            Err(AppError::InvalidToken(
+                "Invalid or expired login token".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::user_model::CreateUser;
+    use crate::utils::config::PasswordConfig;
+    use crate::utils::password::PasswordHasher;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+    const STEP: u64 = 56_000_000;
+
+    fn code_at(totp: &TOTP, step: u64) -> String {
+        totp.generate(step * TOTP_STEP_SECONDS)
+    }
+
+    #[test]
+    fn codes_match_the_current_step_and_its_neighbours() {
+        let totp = TwoFactor::totp(&TwoFactorConfig::default(), SECRET, "a@example.com").unwrap();
+
+        for step in STEP - 1..=STEP + 1 {
+            assert_eq!(
+                TwoFactor::matching_step(&totp, &code_at(&totp, step), STEP),
+                Some(step)
+            );
+        }
+        assert_eq!(
+            TwoFactor::matching_step(&totp, &code_at(&totp, STEP - 2), STEP),
+            None
+        );
+        assert_eq!(
+            TwoFactor::matching_step(&totp, &code_at(&totp, STEP + 2), STEP),
+            None
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    fn totp_codes_cannot_be_replayed() {
+        let url = std::env::var("TEST_DATABASE_URL").unwrap();
+        let mut conn = PgConnection::establish(&url).unwrap();
+        crate::db::run_pending(&mut conn).unwrap();
+        let config = TwoFactorConfig::default();
+        let hasher = PasswordHasher::new(&PasswordConfig {
+            bcrypt_cost: 4,
+            argon2_memory_kib: 64,
+            argon2_iterations: 1,
+            ..Default::default()
+        })
+        .unwrap();
+
+        conn.test_transaction::<_, AppError, _>(|conn| {
+            let user = User::add_user(
+                conn,
+                &hasher,
+                CreateUser {
+                    full_name: "Replay".to_string(),
+                    email: format!("{}@example.com", Uuid::new_v4()),
+                    password: "correct horse battery staple".to_string(),
+                },
+            )?;
+            User::update_user(
+                conn,
+                user.id,
+                &UserChanges {
+                    totp_secret: Some(Some(SECRET.to_string())),
+                    totp_enabled_at: Some(Some(Utc::now().naive_utc())),
+                    ..Default::default()
+                },
+            )?;
+            let user = User::find_user_by_id(conn, user.id)?;
+            let totp = TwoFactor::totp(&config, SECRET, &user.email)?;
+
+            let code = code_at(&totp, STEP);
+            assert!(TwoFactor::verify_totp_at(
+                conn, &config, &user, &code, STEP
+            )?);
+            assert!(!TwoFactor::verify_totp_at(
+                conn, &config, &user, &code, STEP
+            )?);
+            // Still inside the drift window, but older than the step just used
+            let previous = code_at(&totp, STEP - 1);
+            assert!(!TwoFactor::verify_totp_at(
+                conn, &config, &user, &previous, STEP
+            )?);
+            // A code from a clock running ahead is fine, and uses up the current step too
+            let next = code_at(&totp, STEP + 1);
+            assert!(TwoFactor::verify_totp_at(
+                conn, &config, &user, &next, STEP
+            )?);
+            assert!(!TwoFactor::verify_totp_at(
+                conn,
+                &config,
+                &user,
+                &code,
+                STEP + 1
+            )?);
+
+            Ok(())
+        });
+    }
+}
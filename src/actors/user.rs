@@ -1,11 +1,12 @@
-use crate::models::user_model::{CreateUser, User};
-use actix_web::Result;
-use bcrypt::{hash, DEFAULT_COST};
+use crate::errors::AppError;
+use crate::models::role_model::Role;
+use crate::models::user_model::{CreateUser, SortOrder, User, UserChanges, UserListQuery};
+use crate::utils::password::PasswordHasher;
 use chrono::Utc;
 use diesel::prelude::*;
 use validator::Validate;
 
-type DbError = Box<dyn std::error::Error + Send + Sync>;
+diesel::sql_function!(fn lower(x: diesel::sql_types::Varchar) -> diesel::sql_types::Varchar);
 
 impl User {
     /// Add a new user to the database
@@ -13,12 +14,17 @@ impl User {
     /// # Parameters
     ///
     /// * `conn` - The database connection
+    /// * `hasher` - The password hasher, using the preferred algorithm
     /// * `data` - The user data to create, including their full name, email address, and password
     ///
     /// # Returns
     ///
-    /// A `UserDetails` struct containing the details of the newly created user, including their full name, email address, password, and creation and update timestamps
-    pub fn add_user(conn: &mut PgConnection, data: CreateUser) -> Result<String, DbError> {
+    /// A `User` struct containing the details of the newly created user, including their full name, email address, password, and creation and update timestamps
+    pub fn add_user(
+        conn: &mut PgConnection,
+        hasher: &PasswordHasher,
+        data: CreateUser,
+    ) -> Result<User, AppError> {
         data.validate()?;
 
         use crate::schema::users::dsl::*;
@@ -29,20 +35,19 @@ impl User {
                 id: uuid::Uuid::new_v4(),
                 full_name: data.full_name,
                 email: data.email,
-                password: hash(data.password.as_bytes(), DEFAULT_COST)?,
+                password: hasher.hash(&data.password)?,
                 created_at: current_time,
                 updated_at: current_time,
+                email_verified_at: None,
+                role: Role::User,
+                suspended_at: None,
+                totp_secret: None,
+                totp_enabled_at: None,
+                totp_last_used_step: None,
             })
-            .execute(conn)?;
+            .get_result::<User>(conn)?;
 
-        if new_user == 1 {
-            Ok("OK".to_string())
-        } else {
-            Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Could not create user",
-            )))
-        }
+        Ok(new_user)
     }
 
     /// Find a user by their email address in the database
@@ -54,38 +59,181 @@ impl User {
     ///
     /// # Returns
     ///
-    /// A `User` struct if a user with the specified email address was found, or an error if not
-    pub fn find_user_by_email(conn: &mut PgConnection, user_email: &str) -> Result<User, DbError> {
+    /// A `User` struct if a user with the specified email address was found, or an error if not.
+    /// The comparison is case insensitive, matching the `users_email_lower_key` unique index.
+    pub fn find_user_by_email(conn: &mut PgConnection, user_email: &str) -> Result<User, AppError> {
         use crate::schema::users::dsl::*;
 
         // Attempt to find the user by email
-        let result = users.filter(email.eq(user_email)).first::<User>(conn)?;
+        let result = users
+            .filter(lower(email).eq(user_email.trim().to_lowercase()))
+            .first::<User>(conn)?;
+
+        Ok(result)
+    }
+
+    /// Find a user by their id in the database
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `user_id` - The id of the user to find
+    ///
+    /// # Returns
+    ///
+    /// A `User` struct if a user with the specified id was found, or an error if not
+    pub fn find_user_by_id(conn: &mut PgConnection, user_id: uuid::Uuid) -> Result<User, AppError> {
+        use crate::schema::users::dsl::*;
+
+        let result = users.find(user_id).first::<User>(conn)?;
 
         Ok(result)
     }
 
-    /// Deletes a user from the database based on their email address
+    /// Deletes a user from the database based on their id
     ///
     /// # Parameters
     ///
     /// * `conn` - The database connection
-    /// * `user_email` - The email address of the user to delete
+    /// * `user_id` - The id of the user to delete
     ///
     /// # Returns
     ///
-    /// A `String` containing the email address of the deleted user
-    pub fn delete_user(conn: &mut PgConnection, user_email: &str) -> Result<String, DbError> {
+    /// The id of the deleted user
+    pub fn delete_user(
+        conn: &mut PgConnection,
+        user_id: uuid::Uuid,
+    ) -> Result<uuid::Uuid, AppError> {
         use crate::schema::users::dsl::*;
         // Delete the user from the database
-        let user_deleted = diesel::delete(users.filter(email.eq(user_email))).execute(conn)?;
+        let user_deleted = diesel::delete(users.find(user_id)).execute(conn)?;
 
-        // Return the email address of the deleted user
+        // Return the id of the deleted user
         match user_deleted {
-            0 => Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Could not delete user",
-            ))),
-            _ => Ok(user_email.to_string()),
+            0 => Err(AppError::NotFound("User not found".to_string())),
+            _ => Ok(user_id),
         }
     }
+
+    /// Marks a user's email address as verified
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `user_id` - The id of the user whose email address was verified
+    pub fn mark_email_verified(
+        conn: &mut PgConnection,
+        user_id: uuid::Uuid,
+    ) -> Result<(), AppError> {
+        use crate::schema::users::dsl::*;
+
+        let current_time = Utc::now().naive_utc();
+        diesel::update(users.find(user_id))
+            .set((
+                email_verified_at.eq(current_time),
+                updated_at.eq(current_time),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Hashes and stores a new password for a user
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `hasher` - The password hasher, using the preferred algorithm
+    /// * `user_id` - The id of the user whose password changes
+    /// * `new_password` - The new plain text password
+    ///
+    /// # Returns
+    ///
+    /// The updated `User`
+    pub fn update_password(
+        conn: &mut PgConnection,
+        hasher: &PasswordHasher,
+        user_id: uuid::Uuid,
+        new_password: &str,
+    ) -> Result<User, AppError> {
+        use crate::schema::users::dsl::*;
+
+        let updated_user = diesel::update(users.find(user_id))
+            .set((
+                password.eq(hasher.hash(new_password)?),
+                updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .get_result::<User>(conn)?;
+
+        Ok(updated_user)
+    }
+
+    /// Lists users page by page, optionally filtered by a search term
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `query` - Pagination, search and ordering options
+    ///
+    /// # Returns
+    ///
+    /// The users on the requested page and the total number of users matching the search
+    pub fn list_users(
+        conn: &mut PgConnection,
+        query: &UserListQuery,
+    ) -> Result<(Vec<User>, i64), AppError> {
+        use crate::schema::users::dsl::*;
+
+        let pattern = query.search.as_deref().map(|term| {
+            let escaped = term
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+        let filtered = || {
+            let mut filtered = users.into_boxed();
+            if let Some(pattern) = &pattern {
+                filtered = filtered.filter(email.ilike(pattern).or(full_name.ilike(pattern)));
+            }
+            filtered
+        };
+
+        let total = filtered().count().get_result::<i64>(conn)?;
+        let ordered = match query.order {
+            SortOrder::Asc => filtered().order(created_at.asc()),
+            SortOrder::Desc => filtered().order(created_at.desc()),
+        };
+        let page = ordered
+            .limit(query.per_page)
+            .offset((query.page - 1) * query.per_page)
+            .load::<User>(conn)?;
+
+        Ok((page, total))
+    }
+
+    /// Applies a set of column changes to a user and bumps `updated_at`
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `user_id` - The id of the user to update
+    /// * `changes` - The columns to change, `None` fields are left untouched
+    ///
+    /// # Returns
+    ///
+    /// The updated `User`
+    pub fn update_user(
+        conn: &mut PgConnection,
+        user_id: uuid::Uuid,
+        changes: &UserChanges,
+    ) -> Result<User, AppError> {
+        use crate::schema::users::dsl::*;
+
+        let updated_user = diesel::update(users.find(user_id))
+            .set((changes, updated_at.eq(Utc::now().naive_utc())))
+            .get_result::<User>(conn)?;
+
+        Ok(updated_user)
+    }
 }
@@ -0,0 +1,101 @@
+use crate::errors::AppError;
+use crate::models::user_model::User;
+use crate::models::verification_model::EmailVerificationToken;
+use crate::utils::tokens::TokenSigner;
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+impl EmailVerificationToken {
+    /// Issue a new verification token for a user, invalidating any previous ones
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `signer` - The signer used to derive the stored token hash
+    /// * `for_user` - The id of the user the token belongs to
+    /// * `ttl` - How long the token stays valid
+    ///
+    /// # Returns
+    ///
+    /// The plain token to send to the user; only its signature is stored
+    pub fn issue(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        for_user: Uuid,
+        ttl: Duration,
+    ) -> Result<String, AppError> {
+        use crate::schema::email_verification_tokens::dsl::*;
+
+        let (token, signature) = signer.generate();
+        let current_time = Utc::now().naive_utc();
+
+        conn.transaction(|conn| {
+            diesel::update(email_verification_tokens.filter(user_id.eq(for_user)))
+                .filter(used_at.is_null())
+                .set(used_at.eq(current_time))
+                .execute(conn)?;
+
+            diesel::insert_into(email_verification_tokens)
+                .values(EmailVerificationToken {
+                    id: Uuid::new_v4(),
+                    user_id: for_user,
+                    token_hash: signature,
+                    expires_at: current_time + ttl,
+                    used_at: None,
+                    created_at: current_time,
+                })
+                .execute(conn)
+        })?;
+
+        Ok(token)
+    }
+
+    /// Consume a verification token and mark the owning user's email as verified
+    ///
+    /// # Parameters
+    ///
+    /// * `conn` - The database connection
+    /// * `signer` - The signer used to derive the stored token hash
+    /// * `token` - The plain token received from the user
+    ///
+    /// # Returns
+    ///
+    /// The id of the verified user, or `AppError::InvalidToken` if the token is unknown, used or expired
+    pub fn consume(
+        conn: &mut PgConnection,
+        signer: &TokenSigner,
+        token: &str,
+    ) -> Result<Uuid, AppError> {
+        use crate::schema::email_verification_tokens::dsl::*;
+
+        let signature = signer.sign(token);
+        let current_time = Utc::now().naive_utc();
+
+        conn.transaction(|conn| {
+            let verification = email_verification_tokens
+                .filter(token_hash.eq(&signature))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(current_time))
+                .select(EmailVerificationToken::as_select())
+                .first(conn)
+                .optional()?
+                .ok_or_else(|| AppError::InvalidToken("Invalid or expired token".to_string()))?;
+
+            // Conditional update so two requests racing with the same token cannot both use it
+            let updated = diesel::update(email_verification_tokens.find(verification.id))
+                .filter(used_at.is_null())
+                .set(used_at.eq(current_time))
+                .execute(conn)?;
+            if updated == 0 {
+                return Err(AppError::InvalidToken(
+                    "Invalid or expired token".to_string(),
+                ));
+            }
+
+            User::mark_email_verified(conn, verification.user_id)?;
+
+            Ok(verification.user_id)
+        })
+    }
+}
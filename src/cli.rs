@@ -0,0 +1,316 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+use actix_redis::{resp_array, RedisActor};
+use clap::{Args, Parser, Subcommand};
+use diesel::{Connection, PgConnection};
+
+use crate::actors::session::SessionIndex;
+use crate::db;
+use crate::errors::AppError;
+use crate::models::refresh_token_model::RefreshToken;
+use crate::models::role_model::Role;
+use crate::models::user_model::{validate_password, CreateUser, User, UserChanges};
+use crate::utils::config::{establish_connection, get_database_connection};
+use crate::utils::email::normalize_email;
+use crate::utils::password::PasswordHasher;
+use crate::utils::redis::{execute, RedisAddr};
+use crate::utils::settings::Settings;
+use crate::utils::tls::load_rustls_config;
+
+/// Accounts server
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Base settings file, `settings.{APP_ENV}.toml` next to it is layered on top
+    #[arg(long, global = true, default_value = "settings.toml")]
+    pub config: String,
+    /// Defaults to `serve`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the HTTP server
+    Serve,
+    /// Apply, revert or list database migrations
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Manage accounts without going through the API
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand)]
+pub enum MigrateCommand {
+    /// Apply every pending migration
+    Up,
+    /// Revert the most recently applied migration
+    Down,
+    /// List migrations and whether they have been applied
+    Status,
+}
+
+/// Commands reading a password take it from standard input, so it never shows up in the
+/// process list or the shell history
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create an account, e.g. the first admin
+    Create(CreateUserArgs),
+    /// Change the role of an account
+    Promote {
+        email: String,
+        /// `user`, `moderator` or `admin`
+        #[arg(long, default_value = "admin", value_parser = parse_role)]
+        role: Role,
+    },
+    /// Set a new password and log the account out everywhere
+    ResetPassword { email: String },
+    /// Delete an account
+    Delete {
+        email: String,
+        /// Confirm the deletion
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct CreateUserArgs {
+    /// Email address, normalized the same way as on signup
+    #[arg(long)]
+    email: String,
+    /// Full name
+    #[arg(long)]
+    name: String,
+    /// `user`, `moderator` or `admin`
+    #[arg(long, default_value = "user", value_parser = parse_role)]
+    role: Role,
+    /// Mark the email address as verified, otherwise the user has to request a verification link
+    #[arg(long)]
+    verified: bool,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Load and validate the configuration, including the TLS certificate
+    Check,
+}
+
+fn parse_role(value: &str) -> Result<Role, String> {
+    [Role::User, Role::Moderator, Role::Admin]
+        .into_iter()
+        .find(|role| role.as_str() == value)
+        .ok_or_else(|| format!("unknown role `{}`", value))
+}
+
+/// `AppError` messages are meant for API clients and hide the details an operator needs
+fn describe(err: AppError) -> String {
+    match err {
+        AppError::Validation(errors) => errors.to_string(),
+        AppError::PoolExhausted(err) => err.to_string(),
+        AppError::Database(err) => err.to_string(),
+        AppError::Redis(details) | AppError::Upstream(details) | AppError::Internal(details) => {
+            details
+        }
+        err => err.to_string(),
+    }
+}
+
+fn read_password() -> Result<String, String> {
+    eprint!("Password: ");
+    io::stderr().flush().map_err(|err| err.to_string())?;
+
+    let mut password = String::new();
+    io::stdin()
+        .read_line(&mut password)
+        .map_err(|err| format!("Failed to read the password: {}", err))?;
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+
+    validate_password(&password).map_err(|err| {
+        err.message
+            .map(|message| format!("Invalid password: {}", message))
+            .unwrap_or_else(|| "Invalid password".to_string())
+    })?;
+
+    Ok(password)
+}
+
+/// Start a Redis actor and wait until it is connected, which it does in the background
+async fn connect_redis(settings: &Settings) -> Result<RedisAddr, String> {
+    let redis = RedisActor::start(settings.redis.address.clone());
+    let mut last_error = String::new();
+    for _ in 0..25 {
+        match execute(&redis, resp_array!["PING"]).await {
+            Ok(_) => return Ok(redis),
+            Err(err) => last_error = describe(err),
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    Err(format!(
+        "Redis at {} is unreachable: {}",
+        settings.redis.address, last_error
+    ))
+}
+
+fn find_user(conn: &mut PgConnection, settings: &Settings, email: &str) -> Result<User, String> {
+    let email = normalize_email(email, settings.auth.lowercase_email_local_part);
+
+    User::find_user_by_email(conn, &email).map_err(|err| match err {
+        AppError::NotFound(_) => format!("No account with the email {}", email),
+        err => describe(err),
+    })
+}
+
+/// Run a migration command with the migrations embedded in the binary, printing what was
+/// applied or reverted
+pub fn migrate(settings: &Settings, command: MigrateCommand) -> Result<(), String> {
+    let pool = establish_connection(&settings.db);
+    let mut conn = get_database_connection(&pool).map_err(describe)?;
+
+    match command {
+        MigrateCommand::Up => {
+            let applied = db::run_pending(&mut conn)?;
+            if applied.is_empty() {
+                println!("Database is up to date");
+            }
+            for version in applied {
+                println!("Applied {}", version);
+            }
+        }
+        MigrateCommand::Down => {
+            let reverted = db::revert_last(&mut conn)?;
+            println!("Reverted {}", reverted);
+        }
+        MigrateCommand::Status => {
+            let known = db::known_versions()?;
+            let applied = db::applied_versions(&mut conn)?;
+            for version in &known {
+                let state = if applied.contains(version) {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!("{:<8} {}", state, version);
+            }
+            // Applied by a newer release, the server refuses to start until it is upgraded
+            for version in applied.iter().filter(|version| !known.contains(version)) {
+                println!("{:<8} {}", "unknown", version);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run an account command with the same actors and rules as the API
+pub async fn user(settings: &Settings, command: UserCommand) -> Result<(), String> {
+    let pool = establish_connection(&settings.db);
+    let mut conn = get_database_connection(&pool).map_err(describe)?;
+    let hasher = PasswordHasher::new(&settings.password).map_err(describe)?;
+
+    match command {
+        UserCommand::Create(args) => {
+            let password = read_password()?;
+            let email = normalize_email(&args.email, settings.auth.lowercase_email_local_part);
+            // All or nothing, so a failed step does not leave a plain unverified user behind
+            let user = conn
+                .transaction(|conn| {
+                    let user = User::add_user(
+                        conn,
+                        &hasher,
+                        CreateUser {
+                            full_name: args.name,
+                            email,
+                            password,
+                        },
+                    )?;
+                    if args.role != Role::User {
+                        User::update_user(
+                            conn,
+                            user.id,
+                            &UserChanges {
+                                role: Some(args.role),
+                                ..Default::default()
+                            },
+                        )?;
+                    }
+                    if args.verified {
+                        User::mark_email_verified(conn, user.id)?;
+                    }
+
+                    Ok::<_, AppError>(user)
+                })
+                .map_err(describe)?;
+            println!(
+                "Created {} {} ({})",
+                args.role.as_str(),
+                user.email,
+                user.id
+            );
+        }
+        UserCommand::Promote { email, role } => {
+            let user = find_user(&mut conn, settings, &email)?;
+            User::update_user(
+                &mut conn,
+                user.id,
+                &UserChanges {
+                    role: Some(role),
+                    ..Default::default()
+                },
+            )
+            .map_err(describe)?;
+            println!("{} is now {}", user.email, role.as_str());
+        }
+        UserCommand::ResetPassword { email } => {
+            let user = find_user(&mut conn, settings, &email)?;
+            let password = read_password()?;
+            // Connected first, so the password is not changed without logging the account out
+            let redis = connect_redis(settings).await?;
+            User::update_password(&mut conn, &hasher, user.id, &password).map_err(describe)?;
+
+            // Same as a reset through the API: whoever holds the old credentials is logged out
+            SessionIndex::revoke_all(&redis, user.id)
+                .await
+                .map_err(describe)?;
+            RefreshToken::revoke_all(&pool, &redis, &settings.jwt, user.id)
+                .await
+                .map_err(describe)?;
+            println!("Password of {} has been reset", user.email);
+        }
+        UserCommand::Delete { email, yes } => {
+            let user = find_user(&mut conn, settings, &email)?;
+            if !yes {
+                return Err(format!("Pass --yes to delete {} ({})", user.email, user.id));
+            }
+            let redis = connect_redis(settings).await?;
+            User::delete_user(&mut conn, user.id).map_err(describe)?;
+
+            SessionIndex::revoke_all(&redis, user.id)
+                .await
+                .map_err(describe)?;
+            println!("Deleted {} ({})", user.email, user.id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Report whether the configuration is usable; loading it already validated every section
+pub fn config(settings: &Settings, command: ConfigCommand) -> Result<(), String> {
+    match command {
+        ConfigCommand::Check => {
+            if let Some(tls) = &settings.server.tls {
+                load_rustls_config(tls).map_err(|err| format!("[server.tls]: {}", err))?;
+            }
+            println!("Configuration is valid");
+        }
+    }
+
+    Ok(())
+}
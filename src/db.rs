@@ -0,0 +1,95 @@
+use diesel::migration::{MigrationSource, MigrationVersion};
+use diesel::pg::Pg;
+use diesel::{sql_query, PgConnection, RunQueryDsl};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+/// Every migration of `src/db/migrations`, compiled into the binary
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("src/db/migrations");
+
+/// Arbitrary key of the Postgres advisory lock held while migrating, so instances
+/// starting together do not race to apply the same migration
+const MIGRATION_LOCK_KEY: i64 = 0x006d_6967_7261_7465;
+
+/// Versions of the migrations embedded in this binary
+pub fn known_versions() -> Result<Vec<MigrationVersion<'static>>, String> {
+    let migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS)
+        .map_err(|err| format!("Failed to load the embedded migrations: {}", err))?;
+
+    Ok(migrations
+        .iter()
+        .map(|migration| migration.name().version().as_owned())
+        .collect())
+}
+
+/// Versions applied to the database
+pub fn applied_versions(conn: &mut PgConnection) -> Result<Vec<MigrationVersion<'static>>, String> {
+    conn.applied_migrations()
+        .map_err(|err| format!("Failed to list the applied migrations: {}", err))
+}
+
+/// Apply every pending migration
+///
+/// # Returns
+///
+/// The versions applied, oldest first
+pub fn run_pending(conn: &mut PgConnection) -> Result<Vec<MigrationVersion<'static>>, String> {
+    let lock = |conn: &mut PgConnection, function: &str| {
+        sql_query(format!("SELECT {}({})", function, MIGRATION_LOCK_KEY))
+            .execute(conn)
+            .map_err(|err| format!("Failed to {} the migration lock: {}", function, err))
+    };
+
+    lock(conn, "pg_advisory_lock")?;
+    let applied = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map(|versions| versions.iter().map(MigrationVersion::as_owned).collect())
+        .map_err(|err| format!("Migration failed: {}", err));
+    lock(conn, "pg_advisory_unlock")?;
+
+    applied
+}
+
+/// Revert the most recently applied migration
+pub fn revert_last(conn: &mut PgConnection) -> Result<MigrationVersion<'static>, String> {
+    conn.revert_last_migration(MIGRATIONS)
+        .map_err(|err| format!("Revert failed: {}", err))
+}
+
+/// Make sure the schema matches this binary before serving requests
+///
+/// Pending migrations are applied when `run_migrations` is set and refused otherwise.
+/// A database with migrations this binary does not know about, e.g. after rolling back
+/// to an older release, is always refused since queries could fail in unexpected ways.
+pub fn prepare_schema(conn: &mut PgConnection, run_migrations: bool) -> Result<(), String> {
+    let known = known_versions()?;
+    let unknown: Vec<String> = applied_versions(conn)?
+        .iter()
+        .filter(|version| !known.contains(version))
+        .map(ToString::to_string)
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "The database schema is ahead of this binary, unknown migrations: {}",
+            unknown.join(", ")
+        ));
+    }
+
+    if run_migrations {
+        for version in run_pending(conn)? {
+            eprintln!("Applied migration {}", version);
+        }
+        return Ok(());
+    }
+
+    let pending = conn
+        .pending_migrations(MIGRATIONS)
+        .map_err(|err| format!("Failed to list the pending migrations: {}", err))?;
+    if !pending.is_empty() {
+        return Err(format!(
+            "{} pending migration(s), run `server migrate up` or set db.run_migrations",
+            pending.len()
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER IF EXISTS set_updated_at ON users;
\ No newline at end of file
@@ -0,0 +1,31 @@
+-- Your SQL goes here
+-- Addresses differing only in case cannot be merged automatically, since they may belong to
+-- different people. Stop with a list of them so an operator can merge or rename them first.
+DO $$
+DECLARE
+    duplicates TEXT;
+BEGIN
+    SELECT string_agg(emails, '; ') INTO duplicates
+    FROM (
+        SELECT string_agg(email, ', ' ORDER BY created_at) AS emails
+        FROM users
+        GROUP BY lower(trim(email))
+        HAVING count(*) > 1
+    ) AS groups;
+
+    IF duplicates IS NOT NULL THEN
+        RAISE EXCEPTION 'Email addresses differing only in case: %', duplicates
+            USING HINT = 'Merge or rename these users, list them with: '
+                'SELECT lower(trim(email)), array_agg(email) FROM users GROUP BY 1 HAVING count(*) > 1';
+    END IF;
+END
+$$;
+
+-- Trim addresses and lowercase their domain, matching `normalize_email`
+UPDATE users
+SET email = substring(trim(email) from '^(.*)@') || lower(substring(trim(email) from '@[^@]*$'))
+WHERE trim(email) LIKE '%@%';
+
+-- Uniqueness is enforced case-insensitively, so Bob@x.com and bob@x.com can never both exist
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_email_key;
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_lower_key ON users (lower(email));
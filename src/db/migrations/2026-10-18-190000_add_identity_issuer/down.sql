@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE user_identities
+    DROP CONSTRAINT IF EXISTS user_identities_issuer_subject_key,
+    DROP COLUMN IF EXISTS issuer;
\ No newline at end of file
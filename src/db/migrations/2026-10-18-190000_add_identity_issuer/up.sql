@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- Identities are keyed on the ID token's issuer and subject. Rows linked before this are
+-- matched by provider name until their next sign in fills the issuer in.
+ALTER TABLE user_identities ADD COLUMN issuer VARCHAR(255);
+ALTER TABLE user_identities
+    ADD CONSTRAINT user_identities_issuer_subject_key UNIQUE (issuer, subject);
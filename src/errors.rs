@@ -0,0 +1,175 @@
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use diesel::r2d2;
+use diesel::result::DatabaseErrorKind;
+use serde_json::{json, Value};
+use thiserror::Error;
+use validator::ValidationErrors;
+
+use crate::response::ErrorResponse;
+
+/// The single error type returned by actors and services.
+///
+/// Every variant renders the same JSON envelope (see `ErrorResponse`) with a
+/// machine-readable `code`, so clients only ever have to parse one shape.
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("Validation failed")]
+    Validation(#[from] ValidationErrors),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    InvalidToken(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("Email address has not been verified")]
+    EmailNotVerified,
+    #[error("Two-factor authentication code required")]
+    TwoFactorRequired,
+    #[error("Too many requests, retry in {retry_after} seconds")]
+    TooManyRequests { retry_after: u64 },
+    #[error("Database connection pool exhausted")]
+    PoolExhausted(#[from] r2d2::PoolError),
+    #[error("Session store unavailable")]
+    Redis(String),
+    #[error("Database error")]
+    Database(#[source] diesel::result::Error),
+    #[error("Identity provider unavailable")]
+    Upstream(String),
+    #[error("Internal server error")]
+    Internal(String),
+}
+
+impl AppError {
+    /// Machine-readable identifier of the error, stable across releases
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "validation_failed",
+            AppError::BadRequest(_) => "invalid_payload",
+            AppError::InvalidToken(_) => "invalid_token",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::EmailNotVerified => "email_not_verified",
+            AppError::TwoFactorRequired => "two_factor_required",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+            AppError::PoolExhausted(_) | AppError::Redis(_) => "service_unavailable",
+            AppError::Database(_) => "database_error",
+            AppError::Upstream(_) => "upstream_error",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Per-field details for validation failures, `None` for everything else
+    fn details(&self) -> Option<Value> {
+        match self {
+            AppError::Validation(errors) => Some(
+                errors
+                    .field_errors()
+                    .into_iter()
+                    .map(|(field, errors)| {
+                        let rules: Vec<Value> = errors
+                            .iter()
+                            .map(|error| json!({ "rule": error.code, "message": error.message }))
+                            .collect();
+                        (field.to_string(), Value::Array(rules))
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+}
+
+impl From<diesel::result::Error> for AppError {
+    fn from(err: diesel::result::Error) -> Self {
+        match err {
+            diesel::result::Error::NotFound => AppError::NotFound("Resource not found".to_string()),
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                match info.constraint_name() {
+                    Some("users_email_lower_key") => {
+                        AppError::Conflict("Email is already in use".to_string())
+                    }
+                    Some("user_identities_provider_subject_key")
+                    | Some("user_identities_issuer_subject_key") => AppError::Conflict(
+                        "This external account is already linked to another user".to_string(),
+                    ),
+                    Some("user_identities_user_id_provider_key") => AppError::Conflict(
+                        "A different account of this provider is already linked".to_string(),
+                    ),
+                    _ => AppError::Conflict("Resource already exists".to_string()),
+                }
+            }
+            err => AppError::Database(err),
+        }
+    }
+}
+
+impl From<bcrypt::BcryptError> for AppError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        AppError::Internal(format!("Password hashing failed: {}", err))
+    }
+}
+
+impl From<argon2::password_hash::Error> for AppError {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        AppError::Internal(format!("Password hashing failed: {}", err))
+    }
+}
+
+impl From<actix_web::error::BlockingError> for AppError {
+    fn from(err: actix_web::error::BlockingError) -> Self {
+        AppError::Internal(format!("Blocking task failed: {}", err))
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) | AppError::BadRequest(_) | AppError::InvalidToken(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unauthorized(_) | AppError::TwoFactorRequired => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) | AppError::EmailNotVerified => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::PoolExhausted(_) | AppError::Redis(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // Internal details are logged, never sent to the client
+        match self {
+            AppError::PoolExhausted(err) => eprintln!("Failed to get database connection: {}", err),
+            AppError::Redis(err) => eprintln!("Redis error: {}", err),
+            AppError::Database(err) => eprintln!("Database error: {}", err),
+            AppError::Upstream(err) => eprintln!("Identity provider error: {}", err),
+            AppError::Internal(err) => eprintln!("Internal error: {}", err),
+            _ => {}
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::TooManyRequests { retry_after } = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+
+        response.json(ErrorResponse {
+            status: "error".to_string(),
+            code: self.code().to_string(),
+            message: self.to_string(),
+            details: self.details(),
+        })
+    }
+}
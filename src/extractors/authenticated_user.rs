@@ -0,0 +1,191 @@
+use std::ops::Deref;
+
+use actix_session::Session;
+use actix_web::{
+    dev::Payload, http::header, http::Method, web, FromRequest, HttpMessage, HttpRequest,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::actors::auth::Auth;
+use crate::errors::AppError;
+use crate::models::api_token_model::{ApiScope, ApiToken, API_TOKEN_PREFIX};
+use crate::models::refresh_token_model::RefreshToken;
+use crate::models::role_model::Permission;
+use crate::models::user_model::User;
+use crate::utils::{
+    config::{with_database_connection, SessionConfig},
+    helpers::DbPool,
+    jwt::JwtSigner,
+    redis::RedisAddr,
+    tokens::TokenSigner,
+};
+
+/// How the current request proved who it comes from
+#[derive(Debug, Clone)]
+pub enum AuthMethod {
+    /// The session cookie of a logged in browser
+    Session,
+    /// A JWT access token from `POST /auth/token`, sent as `Authorization: Bearer`
+    AccessToken,
+    /// A personal API token sent as `Authorization: Bearer`
+    ApiToken { scopes: Vec<ApiScope> },
+}
+
+/// The user the current request is authenticated as, including their role
+///
+/// Accepts the session cookie, a JWT access token or a personal API token in an
+/// `Authorization: Bearer` header, and loads the `User` it belongs to, so handlers that take this extractor never run
+/// for anonymous requests, revoked sessions or tokens, deleted or suspended accounts.
+/// Requests made with a token must use a method its scopes allow.
+/// The loaded user is cached in the request extensions, so extracting it again
+/// (e.g. after `RequirePermission`) does not hit Redis or the database twice.
+#[derive(Clone)]
+pub struct AuthenticatedUser(pub User, pub AuthMethod);
+
+impl AuthenticatedUser {
+    /// Fails with `AppError::Forbidden` unless the user's role grants `permission`
+    ///
+    /// API tokens additionally need the `admin` scope to use any permission.
+    pub fn require(&self, permission: Permission) -> Result<(), AppError> {
+        if !self.role.has_permission(permission) {
+            return Err(AppError::Forbidden(
+                "You do not have permission to perform this action".to_string(),
+            ));
+        }
+        if let AuthMethod::ApiToken { scopes, .. } = &self.1 {
+            if !scopes.contains(&ApiScope::Admin) {
+                return Err(AppError::Forbidden(
+                    "API token is missing the admin scope".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fails with `AppError::Forbidden` if the request was made with a personal API token
+    ///
+    /// Used for account security actions, so a leaked token cannot take over the account.
+    pub fn reject_api_token(&self) -> Result<(), AppError> {
+        match self.1 {
+            AuthMethod::Session | AuthMethod::AccessToken => Ok(()),
+            AuthMethod::ApiToken { .. } => Err(AppError::Forbidden(
+                "This action is not available to API tokens".to_string(),
+            )),
+        }
+    }
+}
+
+impl Deref for AuthenticatedUser {
+    type Target = User;
+
+    fn deref(&self) -> &User {
+        &self.0
+    }
+}
+
+/// Token from an `Authorization: Bearer` header, if the request has one
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        if let Some(user) = req.extensions().get::<AuthenticatedUser>() {
+            let user = user.clone();
+            return Box::pin(async move { Ok(user) });
+        }
+
+        let req = req.clone();
+        let session = Session::extract(&req);
+        let pool = req.app_data::<web::Data<DbPool>>().cloned();
+        let redis = req.app_data::<web::Data<RedisAddr>>().cloned();
+        let signer = req.app_data::<web::Data<TokenSigner>>().cloned();
+        let jwt_signer = req.app_data::<web::Data<JwtSigner>>().cloned();
+        let session_config = req.app_data::<web::Data<SessionConfig>>().cloned();
+
+        Box::pin(async move {
+            let session = session
+                .await
+                .map_err(|err| AppError::Internal(format!("Failed to load session: {}", err)))?;
+            let (pool, redis) = pool.zip(redis).ok_or_else(|| {
+                AppError::Internal("Database pool or Redis is not configured".to_string())
+            })?;
+
+            // A bearer token takes precedence, a bad one is never retried as a cookie session
+            let (user_id, method) = match bearer_token(&req) {
+                Some(token) if !token.starts_with(API_TOKEN_PREFIX) => {
+                    let jwt_signer = jwt_signer.ok_or_else(|| {
+                        AppError::Internal("JWT signer is not configured".to_string())
+                    })?;
+                    let claims = jwt_signer.verify(&token)?;
+                    if RefreshToken::is_family_revoked(&redis, claims.fam).await? {
+                        return Err(AppError::Unauthorized(
+                            "Invalid or expired access token".to_string(),
+                        ));
+                    }
+
+                    (claims.sub, AuthMethod::AccessToken)
+                }
+                Some(token) => {
+                    let signer = signer.ok_or_else(|| {
+                        AppError::Internal("Token signer is not configured".to_string())
+                    })?;
+                    let api_token = with_database_connection(&pool, move |conn| {
+                        ApiToken::authenticate(conn, &signer, &token)
+                    })
+                    .await?;
+
+                    let scopes = api_token.scopes();
+                    let needed = match *req.method() {
+                        Method::GET | Method::HEAD | Method::OPTIONS => ApiScope::Read,
+                        _ => ApiScope::Write,
+                    };
+                    if !scopes.contains(&needed) {
+                        return Err(AppError::Forbidden(format!(
+                            "API token is missing the {} scope",
+                            needed.as_str()
+                        )));
+                    }
+
+                    (api_token.user_id, AuthMethod::ApiToken { scopes })
+                }
+                None => {
+                    let session_config = session_config.ok_or_else(|| {
+                        AppError::Internal("Session settings are not configured".to_string())
+                    })?;
+
+                    (
+                        Auth::validate_session(&session, &redis, &session_config).await?,
+                        AuthMethod::Session,
+                    )
+                }
+            };
+
+            let user = match with_database_connection(&pool, move |conn| {
+                User::find_user_by_id(conn, user_id)
+            })
+            .await
+            {
+                Ok(user) if user.suspended_at.is_none() => AuthenticatedUser(user, method),
+                Ok(_) | Err(AppError::NotFound(_)) => {
+                    if let AuthMethod::Session = method {
+                        session.purge();
+                    }
+                    return Err(AppError::Unauthorized("Unauthorized".to_string()));
+                }
+                Err(err) => return Err(err),
+            };
+
+            req.extensions_mut().insert(user.clone());
+            Ok(user)
+        })
+    }
+}
@@ -0,0 +1,49 @@
+use std::ops::Deref;
+
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+use validator::Validate;
+
+use crate::errors::AppError;
+
+/// A `web::Json<T>` that also runs `T::validate()` before the handler is called
+///
+/// Malformed bodies are rejected with `AppError::BadRequest` and rule violations with
+/// `AppError::Validation`, so handlers only ever see data that passed validation.
+pub struct ValidatedJson<T>(pub T);
+
+impl<T> ValidatedJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for ValidatedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> FromRequest for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+
+        Box::pin(async move {
+            let data = json
+                .await
+                .map_err(|err| AppError::BadRequest(err.to_string()))?
+                .into_inner();
+            data.validate()?;
+            Ok(ValidatedJson(data))
+        })
+    }
+}
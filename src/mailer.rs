@@ -0,0 +1,110 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::errors::AppError;
+use crate::utils::config::{MailBackend, MailConfig};
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    pub fn verification(to: &str, link: &str) -> Self {
+        Email {
+            to: to.to_string(),
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Welcome!\n\nPlease confirm your email address by opening the link below:\n\n{}\n\nIf you did not create an account you can ignore this message.\n",
+                link
+            ),
+        }
+    }
+
+    pub fn account_exists(to: &str, link: &str) -> Self {
+        Email {
+            to: to.to_string(),
+            subject: "You already have an account".to_string(),
+            body: format!(
+                "Someone tried to sign up with this email address, but you already have an account.\n\nIf it was you, you can log in, or reset your password by opening the link below:\n\n{}\n\nIf it was not you, you can ignore this message.\n",
+                link
+            ),
+        }
+    }
+
+    pub fn password_reset(to: &str, link: &str) -> Self {
+        Email {
+            to: to.to_string(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Someone asked to reset the password of your account.\n\nOpen the link below to choose a new password:\n\n{}\n\nIf you did not ask for this you can ignore this message, your password has not been changed.\n",
+                link
+            ),
+        }
+    }
+}
+
+/// Delivers outgoing emails
+///
+/// Handlers receive the configured implementation as `web::Data<dyn Mailer>`.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), AppError>;
+}
+
+/// Prints emails to stderr, for local development
+pub struct LogMailer {
+    from: String,
+}
+
+impl Mailer for LogMailer {
+    fn send(&self, email: &Email) -> Result<(), AppError> {
+        eprintln!(
+            "[mail] from: {} to: {} subject: {}\n{}",
+            self.from, email.to, email.subject, email.body
+        );
+        Ok(())
+    }
+}
+
+/// Writes every email to its own file in `outbox_dir`, for development and tests
+pub struct FileMailer {
+    from: String,
+    dir: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), AppError> {
+        let write = || -> std::io::Result<()> {
+            fs::create_dir_all(&self.dir)?;
+            let file_name = format!(
+                "{}-{}.eml",
+                Utc::now().format("%Y%m%d%H%M%S%.f"),
+                uuid::Uuid::new_v4()
+            );
+            let message = format!(
+                "From: {}\nTo: {}\nSubject: {}\n\n{}",
+                self.from, email.to, email.subject, email.body
+            );
+            fs::write(self.dir.join(file_name), message)
+        };
+
+        write().map_err(|err| AppError::Internal(format!("Failed to write email: {}", err)))
+    }
+}
+
+pub fn build_mailer(config: &MailConfig) -> Arc<dyn Mailer> {
+    match config.backend {
+        MailBackend::Log => Arc::new(LogMailer {
+            from: config.from.clone(),
+        }),
+        MailBackend::File => Arc::new(FileMailer {
+            from: config.from.clone(),
+            dir: PathBuf::from(&config.outbox_dir),
+        }),
+    }
+}
@@ -1,41 +1,209 @@
+use actix_redis::RedisActor;
 use actix_session::config::PersistentSession;
 use actix_session::{storage::RedisActorSessionStore, SessionMiddleware};
-use actix_web::{cookie::time, web, App, HttpServer};
+use actix_web::{cookie::time, http::KeepAlive, web, App, HttpServer};
 
 mod actors;
+mod cli;
+mod db;
+mod errors;
+mod extractors;
+mod mailer;
+mod middleware;
 mod models;
 mod response;
 mod schema;
 mod services;
 mod utils;
-use services::{auth::login, user::create_user, user::delete_user};
-use utils::config::{establish_connection, load_config};
+use clap::Parser;
+use cli::{Cli, Command};
+use errors::AppError;
+use mailer::{build_mailer, Mailer};
+use middleware::rate_limit::RateLimit;
+use services::{
+    admin,
+    api_token::{create_token, list_tokens, revoke_token},
+    auth::{
+        complete_two_factor_login, forgot_password, issue_token, login, logout, logout_all,
+        refresh_access_token, reset_password, revoke_refresh_token,
+    },
+    oidc::{link_identity, list_identities, oidc_callback, oidc_login, unlink_identity},
+    user::{
+        change_password, confirm_two_factor, create_user, delete_user, disable_two_factor,
+        get_profile, list_permissions, list_sessions, regenerate_recovery_codes,
+        resend_verification_email, revoke_session, setup_two_factor, update_profile, verify_email,
+    },
+};
+use utils::config::{establish_connection, get_database_connection, UNIX_SOCKET_PREFIX};
 use utils::helpers::get_secret_key;
+use utils::jwt::JwtSigner;
+use utils::password::PasswordHasher;
+use utils::rate_limit_store::build_rate_limit_store;
+use utils::settings::Settings;
+use utils::tls::load_rustls_config;
+use utils::tokens::TokenSigner;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let config_values = load_config("settings.toml").expect("Failed to load config");
-    let secret_val = config_values["server"]["key_secret"].as_str().unwrap();
-    let secret_key = get_secret_key(secret_val);
-    let pool = establish_connection(config_values["db"]["pgdb"].as_str().unwrap());
-    HttpServer::new(move || {
+    let cli = Cli::parse();
+    let settings = Settings::load(&cli.config).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+
+    let result = match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => return serve(settings).await,
+        Command::Migrate(command) => cli::migrate(&settings, command),
+        Command::User(command) => cli::user(&settings, command).await,
+        Command::Config(command) => cli::config(&settings, command),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+async fn serve(settings: Settings) -> std::io::Result<()> {
+    let secret_key = get_secret_key(&settings.security.key_secret);
+    let token_signer = TokenSigner::new(&settings.security.key_secret);
+    let jwt_signer = JwtSigner::new(&settings.security.jwt_secret);
+    let password_hasher =
+        PasswordHasher::new(&settings.password).expect("Invalid [password] config");
+    let http_client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .expect("Failed to build HTTP client");
+    let mailer: web::Data<dyn Mailer> = web::Data::from(build_mailer(&settings.mail));
+    let pool = establish_connection(&settings.db);
+    let schema_ready = get_database_connection(&pool)
+        .map_err(|err| format!("Failed to connect to the database: {}", err))
+        .and_then(|mut conn| db::prepare_schema(&mut conn, settings.db.run_migrations));
+    if let Err(err) = schema_ready {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+    let redis = RedisActor::start(settings.redis.address.clone());
+    // Built once so every worker draws from the same buckets
+    let rate_limit_store = build_rate_limit_store(&settings.rate_limit, &redis);
+    let app_settings = settings.clone();
+    let mut server = HttpServer::new(move || {
+        let settings = &app_settings;
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(redis.clone()))
+            .app_data(web::Data::new(token_signer.clone()))
+            .app_data(web::Data::new(jwt_signer.clone()))
+            .app_data(web::Data::new(password_hasher.clone()))
+            .app_data(web::Data::new(settings.session.clone()))
+            .app_data(web::Data::new(settings.auth.clone()))
+            .app_data(web::Data::new(settings.login_throttle.clone()))
+            .app_data(web::Data::new(settings.jwt.clone()))
+            .app_data(web::Data::new(settings.two_factor.clone()))
+            .app_data(web::Data::new(settings.oidc.clone()))
+            .app_data(web::Data::new(http_client.clone()))
+            .app_data(web::Data::new(settings.mail.clone()))
+            .app_data(mailer.clone())
+            .app_data(web::JsonConfig::default().limit(settings.server.max_json_bytes))
+            .app_data(web::PayloadConfig::new(settings.server.max_payload_bytes))
+            // Unparsable query strings and path segments get the same JSON envelope as every other error
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|err, _req| AppError::BadRequest(err.to_string()).into()),
+            )
+            .app_data(web::PathConfig::default().error_handler(|_err, _req| {
+                AppError::NotFound("Resource not found".to_string()).into()
+            }))
+            // Registered before the session middleware so it runs inside it and can read the session
+            .wrap(RateLimit::new(
+                settings.rate_limit.clone(),
+                rate_limit_store.clone(),
+            ))
             .wrap(
                 SessionMiddleware::builder(
-                    RedisActorSessionStore::new(config_values["db"]["rdb"].as_str().unwrap()),
+                    RedisActorSessionStore::new(&settings.redis.address),
                     secret_key.clone(),
                 )
                 .session_lifecycle(
-                    PersistentSession::default().session_ttl(time::Duration::days(14)),
+                    PersistentSession::default()
+                        .session_ttl(time::Duration::days(settings.session.ttl_days)),
                 )
+                .cookie_name(settings.session.cookie_name.clone())
+                .cookie_secure(settings.session.cookie_secure)
+                .cookie_same_site(settings.session.cookie_same_site.into())
+                .cookie_domain(settings.session.cookie_domain.clone())
                 .build(),
             )
             .service(create_user)
+            .service(verify_email)
+            .service(resend_verification_email)
             .service(login)
+            .service(complete_two_factor_login)
+            .service(issue_token)
+            .service(refresh_access_token)
+            .service(revoke_refresh_token)
+            .service(oidc_login)
+            .service(oidc_callback)
+            .service(logout)
+            .service(logout_all)
+            .service(forgot_password)
+            .service(reset_password)
+            .service(get_profile)
+            .service(update_profile)
             .service(delete_user)
+            .service(change_password)
+            .service(list_sessions)
+            .service(revoke_session)
+            .service(list_permissions)
+            .service(setup_two_factor)
+            .service(confirm_two_factor)
+            .service(disable_two_factor)
+            .service(regenerate_recovery_codes)
+            .service(create_token)
+            .service(list_tokens)
+            .service(revoke_token)
+            .service(list_identities)
+            .service(link_identity)
+            .service(unlink_identity)
+            .service(admin::list_users)
+            .service(admin::get_user)
+            .service(admin::update_user)
+            .service(admin::delete_user)
+            .service(admin::list_lockouts)
+            .service(admin::clear_user_lockout)
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .keep_alive(match settings.server.keep_alive_seconds {
+        0 => KeepAlive::Disabled,
+        seconds => KeepAlive::Timeout(std::time::Duration::from_secs(seconds)),
+    });
+    if let Some(workers) = settings.server.workers {
+        server = server.workers(workers);
+    }
+
+    let tls_config = settings
+        .server
+        .tls
+        .as_ref()
+        .map(load_rustls_config)
+        .transpose()?;
+    for address in &settings.server.listen {
+        server = match (address.strip_prefix(UNIX_SOCKET_PREFIX), &tls_config) {
+            #[cfg(unix)]
+            (Some(path), _) => server.bind_uds(path)?,
+            #[cfg(not(unix))]
+            (Some(_), _) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Unix sockets are not supported on this platform",
+                ))
+            }
+            (None, Some(tls_config)) => {
+                server.bind_rustls_021(address.as_str(), tls_config.clone())?
+            }
+            (None, None) => server.bind(address.as_str())?,
+        };
+    }
+
+    server.run().await
 }
@@ -0,0 +1,345 @@
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_session::SessionExt;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::{web, ResponseError};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::api_token_model::{ApiToken, API_TOKEN_PREFIX};
+use crate::utils::config::{
+    with_database_connection, RateLimitConfig, RateLimitKey, RateLimitRule,
+};
+use crate::utils::helpers::{client_ip, DbPool};
+use crate::utils::rate_limit_store::{RateLimitDecision, RateLimitStore};
+use crate::utils::tokens::TokenSigner;
+
+/// Applies the token-bucket rules of the `[rate_limit]` section to every request
+///
+/// Wrapped on the whole app, inside the session middleware so `key = "user"` rules can read the
+/// session. Limited responses carry `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`
+/// headers, rejected ones are a 429 with `Retry-After`. If the store is unreachable requests are
+/// let through rather than taking the whole API down.
+#[derive(Clone)]
+pub struct RateLimit {
+    config: Rc<RateLimitConfig>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimit {
+    pub fn new(config: RateLimitConfig, store: Arc<dyn RateLimitStore>) -> Self {
+        RateLimit {
+            config: Rc::new(config),
+            store,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            config: Rc::clone(&self.config),
+            store: Arc::clone(&self.store),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    config: Rc<RateLimitConfig>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        let rule_index = if self.config.enabled {
+            self.config
+                .rules
+                .iter()
+                .position(|rule| rule_matches(rule, &req))
+        } else {
+            None
+        };
+        let Some(rule_index) = rule_index else {
+            return Box::pin(async move {
+                service
+                    .call(req)
+                    .await
+                    .map(ServiceResponse::map_into_left_body)
+            });
+        };
+
+        let rule = self.config.rules[rule_index].clone();
+        let trust_proxy_headers = self.config.trust_proxy_headers;
+        let store = Arc::clone(&self.store);
+
+        Box::pin(async move {
+            let key = format!(
+                "{}:{}",
+                rule_index,
+                request_key(rule.key, &req, trust_proxy_headers).await
+            );
+            let decision = match store.acquire(key, rule.limit, rule.period_seconds).await {
+                Ok(decision) => decision,
+                Err(err) => {
+                    eprintln!(
+                        "Rate limit store unavailable, letting request through: {}",
+                        err
+                    );
+                    return service
+                        .call(req)
+                        .await
+                        .map(ServiceResponse::map_into_left_body);
+                }
+            };
+
+            if !decision.allowed {
+                let err = AppError::TooManyRequests {
+                    retry_after: decision.retry_after_seconds.max(1),
+                };
+                let mut response = req.into_response(err.error_response());
+                insert_headers(response.headers_mut(), &decision);
+                return Ok(response.map_into_right_body());
+            }
+
+            let mut response = service.call(req).await?;
+            insert_headers(response.headers_mut(), &decision);
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &RateLimitDecision) {
+    let values = [
+        ("ratelimit-limit", decision.limit as u64),
+        ("ratelimit-remaining", decision.remaining as u64),
+        ("ratelimit-reset", decision.reset_seconds),
+    ];
+    for (name, value) in values {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+/// Whether `rule` applies to the method and path of `req`
+fn rule_matches(rule: &RateLimitRule, req: &ServiceRequest) -> bool {
+    let method_matches = rule.methods.is_empty()
+        || rule
+            .methods
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(req.method().as_str()));
+
+    // The router matches the percent-decoded path, so must the rules, otherwise
+    // `/user/%63reate` would reach `/user/create` without counting against its rule
+    method_matches && path_matches(&rule.pattern, req.match_info().as_str())
+}
+
+/// Matches `path` against a route pattern where `{name}` stands for one segment and a
+/// trailing `*` for the rest of the path
+///
+/// Rate limiting runs before routing, so the router's own matching is not available yet.
+/// `path` must already be percent-decoded the same way the router decodes it.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut path_segments = path.trim_matches('/').split('/');
+
+    for pattern_segment in pattern.trim_matches('/').split('/') {
+        if pattern_segment == "*" {
+            return true;
+        }
+        match path_segments.next() {
+            Some(segment) if pattern_segment.starts_with('{') && pattern_segment.ends_with('}') => {
+                if segment.is_empty() {
+                    return false;
+                }
+            }
+            Some(segment) if segment == pattern_segment => {}
+            _ => return false,
+        }
+    }
+
+    path_segments.next().is_none()
+}
+
+/// Identifies whose bucket `req` is counted against
+async fn request_key(key: RateLimitKey, req: &ServiceRequest, trust_proxy_headers: bool) -> String {
+    let subject = match key {
+        RateLimitKey::Ip => None,
+        RateLimitKey::User => req
+            .get_session()
+            .get::<Uuid>("user_id")
+            .ok()
+            .flatten()
+            .map(|id| format!("user:{}", id)),
+        RateLimitKey::ApiKey => api_token_id(req).await.map(|id| format!("api_key:{}", id)),
+    };
+
+    subject.unwrap_or_else(|| format!("ip:{}", client_ip(req.request(), trust_proxy_headers)))
+}
+
+/// Id of the personal API token sent with `req`, `None` unless it is a valid one
+///
+/// Keying on the header value itself would give every made-up value a fresh bucket.
+async fn api_token_id(req: &ServiceRequest) -> Option<Uuid> {
+    let headers = req.headers();
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            headers
+                .get("X-Api-Key")
+                .and_then(|value| value.to_str().ok())
+        })
+        .map(str::trim)
+        .filter(|value| value.starts_with(API_TOKEN_PREFIX))?
+        .to_string();
+    let pool = req.app_data::<web::Data<DbPool>>()?.clone();
+    let signer = req.app_data::<web::Data<TokenSigner>>()?.clone();
+
+    match with_database_connection(&pool, move |conn| {
+        ApiToken::authenticate(conn, &signer, &token)
+    })
+    .await
+    {
+        Ok(api_token) => Some(api_token.id),
+        Err(AppError::Unauthorized(_)) => None,
+        Err(err) => {
+            eprintln!("Failed to check API token for rate limiting: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::rate_limit_store::MemoryRateLimitStore;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    fn config(key: RateLimitKey, limit: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            rules: vec![RateLimitRule {
+                pattern: "/user/create".to_string(),
+                methods: vec!["POST".to_string()],
+                key,
+                limit,
+                period_seconds: 3600,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn path_matches_segments_placeholders_and_wildcards() {
+        assert!(path_matches("/user/create", "/user/create"));
+        assert!(path_matches("/user/create", "/user/create/"));
+        assert!(!path_matches("/user/create", "/user/create/extra"));
+        assert!(!path_matches("/user/create", "/user"));
+        assert!(path_matches(
+            "/user/me/sessions/{id}",
+            "/user/me/sessions/abc"
+        ));
+        assert!(!path_matches(
+            "/user/me/sessions/{id}",
+            "/user/me/sessions/"
+        ));
+        assert!(path_matches("/admin/*", "/admin/users/1"));
+        assert!(path_matches("/*", "/anything/at/all"));
+        assert!(!path_matches("/admin/*", "/user/me"));
+    }
+
+    #[actix_web::test]
+    async fn limited_responses_carry_rate_limit_headers() {
+        let store = Arc::new(MemoryRateLimitStore::default());
+        let app = init_service(
+            App::new()
+                .wrap(RateLimit::new(config(RateLimitKey::Ip, 2), store))
+                .route("/user/create", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let header = |response: &ServiceResponse<_>, name: &str| {
+            response
+                .headers()
+                .get(name)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        for remaining in ["1", "0"] {
+            let response =
+                call_service(&app, TestRequest::post().uri("/user/create").to_request()).await;
+            assert_eq!(response.status(), 200);
+            assert_eq!(header(&response, "ratelimit-limit"), "2");
+            assert_eq!(header(&response, "ratelimit-remaining"), remaining);
+        }
+
+        // Percent-encoded paths reach the same route, so they count against the same rule
+        let response =
+            call_service(&app, TestRequest::post().uri("/user/%63reate").to_request()).await;
+        assert_eq!(response.status(), 429);
+        assert_eq!(header(&response, "ratelimit-remaining"), "0");
+        assert_eq!(header(&response, "ratelimit-reset"), "3600");
+        assert_eq!(header(&response, "retry-after"), "1800");
+    }
+
+    #[actix_web::test]
+    async fn made_up_api_keys_share_the_client_ip_bucket() {
+        let store = Arc::new(MemoryRateLimitStore::default());
+        let app = init_service(
+            App::new()
+                .wrap(RateLimit::new(config(RateLimitKey::ApiKey, 2), store))
+                .route("/user/create", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let mut statuses = Vec::new();
+        for index in 0..3 {
+            let request = TestRequest::post()
+                .uri("/user/create")
+                .insert_header((
+                    "X-Api-Key",
+                    format!("{}made-up-{}", API_TOKEN_PREFIX, index),
+                ))
+                .to_request();
+            statuses.push(call_service(&app, request).await.status().as_u16());
+        }
+        for index in 3..5 {
+            let request = TestRequest::post()
+                .uri("/user/create")
+                .insert_header((header::AUTHORIZATION, format!("Bearer random-{}", index)))
+                .to_request();
+            statuses.push(call_service(&app, request).await.status().as_u16());
+        }
+
+        assert_eq!(statuses, vec![200, 200, 429, 429, 429]);
+    }
+}
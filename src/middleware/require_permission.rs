@@ -0,0 +1,63 @@
+use std::rc::Rc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+
+use crate::extractors::authenticated_user::AuthenticatedUser;
+use crate::models::role_model::Permission;
+
+/// Rejects requests whose authenticated user lacks `permission`
+///
+/// Declared on a handler with `#[get("/path", wrap = "RequirePermission(Permission::ViewUsers)")]`
+/// or on a whole scope with `.wrap(...)`. Anonymous requests get a 401, users without the
+/// permission a 403, and the handler can still take `AuthenticatedUser` at no extra cost.
+#[derive(Clone, Copy)]
+pub struct RequirePermission(pub Permission);
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = RequirePermissionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            permission: self.0,
+        }))
+    }
+}
+
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    permission: Permission,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let permission = self.permission;
+
+        Box::pin(async move {
+            let user = req.extract::<AuthenticatedUser>().await?;
+            user.require(permission)?;
+
+            service.call(req).await
+        })
+    }
+}
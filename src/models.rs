@@ -1 +1,10 @@
+pub mod api_token_model;
+pub mod identity_model;
+pub mod lockout_model;
+pub mod password_reset_model;
+pub mod refresh_token_model;
+pub mod role_model;
+pub mod session_model;
+pub mod two_factor_model;
 pub mod user_model;
+pub mod verification_model;
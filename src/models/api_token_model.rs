@@ -0,0 +1,104 @@
+use crate::schema::api_tokens;
+use chrono::NaiveDateTime;
+use diesel::{Insertable, Queryable, Selectable};
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+
+/// Prefix of every personal API token, so they are easy to recognize in logs and secret scanners
+pub const API_TOKEN_PREFIX: &str = "pat_";
+
+/// What a personal API token may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiScope {
+    /// `GET` and `HEAD` requests
+    Read,
+    /// Every other method
+    Write,
+    /// Actions that need a role permission, on top of the user actually having it
+    Admin,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::Read => "read",
+            ApiScope::Write => "write",
+            ApiScope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(ApiScope::Read),
+            "write" => Some(ApiScope::Write),
+            "admin" => Some(ApiScope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// A personal access token, stored as the signature of the secret handed to the user
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = api_tokens)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl ApiToken {
+    /// Scopes granted to the token, ignoring any that are no longer recognized
+    pub fn scopes(&self) -> Vec<ApiScope> {
+        self.scopes
+            .iter()
+            .filter_map(|scope| ApiScope::parse(scope))
+            .collect()
+    }
+}
+
+/// A token as listed to its owner, without the hash
+#[derive(Debug, Serialize)]
+pub struct ApiTokenDetails {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<ApiToken> for ApiTokenDetails {
+    fn from(token: ApiToken) -> Self {
+        ApiTokenDetails {
+            scopes: token.scopes(),
+            id: token.id,
+            name: token.name,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+            created_at: token.created_at,
+        }
+    }
+}
+
+/// Body of `POST /user/me/tokens`
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateApiToken {
+    #[validate(length(
+        min = 1,
+        max = 100,
+        message = "Must be between 1 and 100 characters long"
+    ))]
+    pub name: String,
+    #[validate(length(min = 1, message = "Must grant at least one scope"))]
+    pub scopes: Vec<ApiScope>,
+    /// Days until the token stops working, never when omitted
+    #[validate(range(min = 1, max = 365, message = "Must be between 1 and 365"))]
+    pub expires_in_days: Option<i64>,
+}
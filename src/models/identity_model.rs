@@ -0,0 +1,63 @@
+use crate::schema::user_identities;
+use chrono::NaiveDateTime;
+use diesel::{Insertable, Queryable, Selectable};
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// An account at an external OpenID Connect provider linked to a user
+#[derive(Debug, Clone, Serialize, Queryable, Selectable, Insertable)]
+#[diesel(table_name = user_identities)]
+pub struct ExternalIdentity {
+    pub id: Uuid,
+    #[serde(skip_serializing)]
+    pub user_id: Uuid,
+    /// Name of the provider in `[oidc.providers]`
+    pub provider: String,
+    /// The provider's stable id of the account, the `sub` claim
+    pub subject: String,
+    /// Email the provider reported at the last sign in, informational only
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_login_at: Option<NaiveDateTime>,
+    /// The `iss` claim of the provider's ID tokens, `None` until an account linked before
+    /// issuers were recorded signs in again
+    pub issuer: Option<String>,
+}
+
+/// Query string the provider redirects back to `/auth/oidc/{provider}/callback` with
+#[derive(Debug, Deserialize)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    /// Set instead of `code` when the user declined or the provider failed
+    pub error: Option<String>,
+}
+
+/// A sign in in progress, stored in Redis under its `state` until the provider redirects back
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcAuthorization {
+    pub provider: String,
+    pub code_verifier: String,
+    /// Sent to the provider and expected back in the ID token, so it cannot be replayed
+    pub nonce: String,
+    /// The user to link the identity to, `None` when signing in
+    pub link_user_id: Option<Uuid>,
+}
+
+/// Claims of the provider's ID token that are used, after `verify_id_token` checked them
+#[derive(Debug, Deserialize)]
+pub struct OidcIdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub nonce: Option<String>,
+}
+
+/// Claims of the provider's userinfo endpoint that are used
+#[derive(Debug, Deserialize)]
+pub struct OidcUserInfo {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
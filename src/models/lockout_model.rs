@@ -0,0 +1,26 @@
+use chrono::NaiveDateTime;
+use serde_derive::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockoutKind {
+    Account,
+    Ip,
+}
+
+impl LockoutKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockoutKind::Account => "account",
+            LockoutKind::Ip => "ip",
+        }
+    }
+}
+
+/// An account email or client IP that is temporarily refused logins
+#[derive(Debug, Clone, Serialize)]
+pub struct Lockout {
+    pub kind: LockoutKind,
+    pub subject: String,
+    pub locked_until: NaiveDateTime,
+}
@@ -0,0 +1,34 @@
+use crate::models::user_model::validate_password;
+use crate::schema::password_reset_tokens;
+use crate::utils::email::trimmed;
+use chrono::NaiveDateTime;
+use diesel::{Insertable, Queryable, Selectable};
+use serde_derive::Deserialize;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = password_reset_tokens)]
+pub struct PasswordResetToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ForgotPassword {
+    #[serde(deserialize_with = "trimmed")]
+    #[validate(email(message = "Must be a valid email address"))]
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ResetPassword {
+    #[validate(length(min = 1, message = "Must not be empty"))]
+    pub token: String,
+    #[validate(custom = "validate_password")]
+    pub password: String,
+}
@@ -0,0 +1,48 @@
+use crate::schema::refresh_tokens;
+use crate::utils::email::trimmed;
+use chrono::NaiveDateTime;
+use diesel::{Insertable, Queryable, Selectable};
+use serde_derive::Deserialize;
+use uuid::Uuid;
+use validator::Validate;
+
+/// A refresh token of the JWT authentication mode
+///
+/// Every refresh replaces the token with a new one of the same `family_id`. A token that
+/// is presented again after being used means it was stolen, and the whole family is revoked.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = refresh_tokens)]
+pub struct RefreshToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub family_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Body of `POST /auth/token`
+#[derive(Deserialize, Debug, Validate)]
+pub struct TokenRequest {
+    #[serde(deserialize_with = "trimmed")]
+    #[validate(email(message = "Must be a valid email address"))]
+    pub email: String,
+    #[validate(length(min = 8, message = "Must be at least 8 characters long"))]
+    pub password: String,
+    /// TOTP or recovery code, required for accounts with two-factor authentication
+    #[validate(length(
+        min = 1,
+        max = 32,
+        message = "Must be between 1 and 32 characters long"
+    ))]
+    pub code: Option<String>,
+}
+
+/// Body of `POST /auth/token/refresh` and `POST /auth/token/revoke`
+#[derive(Deserialize, Debug, Validate)]
+pub struct RefreshTokenRequest {
+    #[validate(length(min = 1, message = "Must not be empty"))]
+    pub refresh_token: String,
+}
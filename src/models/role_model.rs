@@ -0,0 +1,78 @@
+use std::io::Write;
+
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Varchar;
+use serde_derive::{Deserialize, Serialize};
+
+/// Actions that are restricted to some roles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Hide or edit hacktivity entries posted by other users
+    ModerateContent,
+    /// List and inspect other accounts
+    ViewUsers,
+    /// Edit, suspend and delete other accounts
+    ManageUsers,
+    /// Change the role of other accounts
+    ManageRoles,
+}
+
+/// Role of a user, stored in `users.role`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = Varchar)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// Every permission granted to the role
+    pub fn permissions(&self) -> &'static [Permission] {
+        match self {
+            Role::User => &[],
+            Role::Moderator => &[Permission::ModerateContent, Permission::ViewUsers],
+            Role::Admin => &[
+                Permission::ModerateContent,
+                Permission::ViewUsers,
+                Permission::ManageUsers,
+                Permission::ManageRoles,
+            ],
+        }
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+}
+
+impl ToSql<Varchar, Pg> for Role {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for Role {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"user" => Ok(Role::User),
+            b"moderator" => Ok(Role::Moderator),
+            b"admin" => Ok(Role::Admin),
+            other => Err(format!("Unrecognized role: {}", String::from_utf8_lossy(other)).into()),
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use chrono::NaiveDateTime;
+use serde_derive::{Deserialize, Serialize};
+
+/// Device metadata recorded for every login, stored in the Redis session index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+}
@@ -0,0 +1,56 @@
+use crate::schema::two_factor_recovery_codes;
+use chrono::NaiveDateTime;
+use diesel::{Insertable, Queryable, Selectable};
+use serde_derive::Deserialize;
+use uuid::Uuid;
+use validator::Validate;
+
+/// A single-use code that stands in for a TOTP code when the authenticator is lost
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = two_factor_recovery_codes)]
+pub struct RecoveryCode {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Body of the 2FA endpoints that only need a code from the authenticator app
+#[derive(Deserialize, Debug, Validate)]
+pub struct TwoFactorCode {
+    #[validate(length(
+        min = 1,
+        max = 32,
+        message = "Must be between 1 and 32 characters long"
+    ))]
+    pub code: String,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct DisableTwoFactor {
+    #[validate(length(min = 1, message = "Must not be empty"))]
+    pub password: String,
+    /// A TOTP code or an unused recovery code
+    #[validate(length(
+        min = 1,
+        max = 32,
+        message = "Must be between 1 and 32 characters long"
+    ))]
+    pub code: String,
+}
+
+/// Body of `POST /auth/login/2fa`
+#[derive(Deserialize, Debug, Validate)]
+pub struct CompleteTwoFactorLogin {
+    /// The pending login token returned by `POST /auth/login`
+    #[validate(length(min = 1, message = "Must not be empty"))]
+    pub token: String,
+    /// A TOTP code or an unused recovery code
+    #[validate(length(
+        min = 1,
+        max = 32,
+        message = "Must be between 1 and 32 characters long"
+    ))]
+    pub code: String,
+}
@@ -1,11 +1,28 @@
+use crate::models::role_model::Role;
 use crate::schema::users;
+use crate::utils::email::{trimmed, trimmed_option};
 use chrono::NaiveDateTime;
-use diesel::{Insertable, Queryable, Selectable};
+use diesel::{AsChangeset, Insertable, Queryable, Selectable};
 use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
-use validator::Validate;
+use validator::{Validate, ValidationError};
 // use validator_derive::Validate;
 
+/// Password policy shared by every payload that sets a password
+///
+/// The upper bound is bcrypt's input limit, which counts bytes rather than characters,
+/// anything longer would be silently truncated.
+pub fn validate_password(password: &str) -> Result<(), ValidationError> {
+    let length = password.len();
+    if !(8..=72).contains(&length) {
+        let mut error = ValidationError::new("length");
+        error.message = Some("Must be between 8 and 72 bytes long".into());
+        return Err(error);
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Queryable, Selectable, Insertable)]
 #[diesel(table_name = users)]
 pub struct User {
@@ -15,9 +32,24 @@ pub struct User {
     pub password: String,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub role: Role,
+    pub suspended_at: Option<NaiveDateTime>,
+    /// Base32 TOTP secret, set during enrollment and only trusted once `totp_enabled_at` is set
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<NaiveDateTime>,
+    /// Last TOTP time step accepted, so a code cannot be used twice
+    pub totp_last_used_step: Option<i64>,
+}
+
+impl User {
+    pub fn two_factor_enabled(&self) -> bool {
+        self.totp_enabled_at.is_some()
+    }
 }
 
 #[derive(Deserialize, Debug, Serialize, Clone, Validate)]
+#[allow(dead_code)]
 pub struct UserIdentity {
     pub id: Uuid,
     pub email: String,
@@ -25,34 +57,168 @@ pub struct UserIdentity {
 
 #[derive(Deserialize, Debug, Serialize, Clone, Validate)]
 pub struct AuthCredentials {
-    #[validate(email)]
+    #[serde(deserialize_with = "trimmed")]
+    #[validate(email(message = "Must be a valid email address"))]
     pub email: String,
-    #[validate(length(min = 8))]
+    #[validate(length(min = 8, message = "Must be at least 8 characters long"))]
     pub password: String,
 }
 
+/// Everything an admin may see about an account, without the password hash
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserDetails {
+    pub id: Uuid,
     pub full_name: String,
     pub email: String,
-    pub password: String,
+    pub role: Role,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub suspended_at: Option<NaiveDateTime>,
+    pub two_factor_enabled: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
+
+impl From<User> for UserDetails {
+    fn from(user: User) -> Self {
+        UserDetails {
+            two_factor_enabled: user.two_factor_enabled(),
+            id: user.id,
+            full_name: user.full_name,
+            email: user.email,
+            role: user.role,
+            email_verified_at: user.email_verified_at,
+            suspended_at: user.suspended_at,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
+/// The logged in user's own profile, without the password hash
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserLoginData {
+    pub id: Uuid,
     pub full_name: String,
     pub email: String,
+    pub role: Role,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub two_factor_enabled: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
 
+impl From<User> for UserLoginData {
+    fn from(user: User) -> Self {
+        UserLoginData {
+            two_factor_enabled: user.two_factor_enabled(),
+            id: user.id,
+            full_name: user.full_name,
+            email: user.email,
+            role: user.role,
+            email_verified_at: user.email_verified_at,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Validate)]
 pub struct CreateUser {
-    #[validate(length(min = 1))]
+    #[validate(length(
+        min = 1,
+        max = 255,
+        message = "Must be between 1 and 255 characters long"
+    ))]
     pub full_name: String,
-    #[validate(email)]
+    #[serde(deserialize_with = "trimmed")]
+    #[validate(email(message = "Must be a valid email address"))]
     pub email: String,
-    #[validate(length(min = 8))]
+    #[validate(custom = "validate_password")]
     pub password: String,
 }
+
+/// Body of `PATCH /user/me`, every field is optional
+#[derive(Debug, Deserialize, Validate)]
+pub struct UpdateProfile {
+    #[validate(length(
+        min = 1,
+        max = 255,
+        message = "Must be between 1 and 255 characters long"
+    ))]
+    pub full_name: Option<String>,
+    #[serde(default, deserialize_with = "trimmed_option")]
+    #[validate(email(message = "Must be a valid email address"))]
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ChangePassword {
+    #[validate(length(min = 1, message = "Must not be empty"))]
+    pub current_password: String,
+    #[validate(custom = "validate_password")]
+    pub new_password: String,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Query string of `GET /admin/users`
+#[derive(Debug, Deserialize, Validate)]
+pub struct UserListQuery {
+    #[serde(default = "UserListQuery::default_page")]
+    #[validate(range(min = 1, message = "Must be at least 1"))]
+    pub page: i64,
+    #[serde(default = "UserListQuery::default_per_page")]
+    #[validate(range(min = 1, max = 100, message = "Must be between 1 and 100"))]
+    pub per_page: i64,
+    /// Matches anywhere in the email address or full name, case insensitive
+    pub search: Option<String>,
+    /// Order by `created_at`
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+impl UserListQuery {
+    fn default_page() -> i64 {
+        1
+    }
+
+    fn default_per_page() -> i64 {
+        20
+    }
+}
+
+/// Body of `PATCH /admin/users/{id}`, every field is optional
+#[derive(Debug, Deserialize, Validate)]
+pub struct AdminUpdateUser {
+    #[validate(length(
+        min = 1,
+        max = 255,
+        message = "Must be between 1 and 255 characters long"
+    ))]
+    pub full_name: Option<String>,
+    #[serde(default, deserialize_with = "trimmed_option")]
+    #[validate(email(message = "Must be a valid email address"))]
+    pub email: Option<String>,
+    pub role: Option<Role>,
+    pub suspended: Option<bool>,
+}
+
+/// Column updates applied by `User::update_user`, `None` leaves a column untouched
+#[derive(Debug, Default, AsChangeset)]
+#[diesel(table_name = users)]
+pub struct UserChanges {
+    pub full_name: Option<String>,
+    pub email: Option<String>,
+    pub role: Option<Role>,
+    pub email_verified_at: Option<Option<NaiveDateTime>>,
+    pub suspended_at: Option<Option<NaiveDateTime>>,
+    pub totp_secret: Option<Option<String>>,
+    pub totp_enabled_at: Option<Option<NaiveDateTime>>,
+    pub totp_last_used_step: Option<Option<i64>>,
+}
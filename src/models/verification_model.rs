@@ -0,0 +1,31 @@
+use crate::schema::email_verification_tokens;
+use crate::utils::email::trimmed;
+use chrono::NaiveDateTime;
+use diesel::{Insertable, Queryable, Selectable};
+use serde_derive::Deserialize;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Debug, Clone, Queryable, Selectable, Insertable)]
+#[diesel(table_name = email_verification_tokens)]
+pub struct EmailVerificationToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct VerifyEmail {
+    #[validate(length(min = 1, message = "Must not be empty"))]
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct ResendVerification {
+    #[serde(deserialize_with = "trimmed")]
+    #[validate(email(message = "Must be a valid email address"))]
+    pub email: String,
+}
@@ -1,6 +1,12 @@
 use serde_derive::Serialize;
+use serde_json::Value;
 
-use crate::models::user_model::{User, UserDetails};
+use crate::models::api_token_model::ApiTokenDetails;
+use crate::models::identity_model::ExternalIdentity;
+use crate::models::lockout_model::Lockout;
+use crate::models::role_model::{Permission, Role};
+use crate::models::session_model::SessionInfo;
+use crate::models::user_model::{UserDetails, UserLoginData};
 
 #[derive(Serialize)]
 pub struct GenericResponse {
@@ -8,6 +14,22 @@ pub struct GenericResponse {
     pub message: String,
 }
 
+#[derive(Serialize, Debug)]
+pub struct ErrorResponse {
+    pub status: String,
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProfileResponse {
+    pub status: String,
+    pub message: String,
+    pub user: UserLoginData,
+}
+
 #[derive(Serialize, Debug)]
 pub struct UserResponse {
     pub status: String,
@@ -19,5 +41,108 @@ pub struct UserResponse {
 pub struct UsersResponse {
     pub status: String,
     pub message: String,
-    pub users: Vec<User>,
+    pub users: Vec<UserDetails>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ActiveSession {
+    #[serde(flatten)]
+    pub info: SessionInfo,
+    /// Whether this is the session the request was made with
+    pub current: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SessionsResponse {
+    pub status: String,
+    pub message: String,
+    pub sessions: Vec<ActiveSession>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PermissionsResponse {
+    pub status: String,
+    pub message: String,
+    pub role: Role,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LockoutsResponse {
+    pub status: String,
+    pub message: String,
+    pub lockouts: Vec<Lockout>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TwoFactorSetupResponse {
+    pub status: String,
+    pub message: String,
+    /// Base32 secret, for authenticator apps that cannot scan the URI
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RecoveryCodesResponse {
+    pub status: String,
+    pub message: String,
+    pub recovery_codes: Vec<String>,
+}
+
+/// Returned by `POST /auth/login` when the password was right but a 2FA code is still needed
+#[derive(Serialize, Debug)]
+pub struct TwoFactorRequiredResponse {
+    pub status: String,
+    pub message: String,
+    /// Pending login token to send to `POST /auth/login/2fa` along with the code
+    pub token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiTokenCreatedResponse {
+    pub status: String,
+    pub message: String,
+    pub token: ApiTokenDetails,
+    /// The token to send as `Authorization: Bearer`, only ever returned here
+    pub secret: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ApiTokensResponse {
+    pub status: String,
+    pub message: String,
+    pub tokens: Vec<ApiTokenDetails>,
+}
+
+/// Tokens issued by `POST /auth/token` and `POST /auth/token/refresh`
+#[derive(Serialize, Debug)]
+pub struct TokenResponse {
+    pub status: String,
+    pub message: String,
+    pub access_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires
+    pub expires_in: i64,
+    pub refresh_token: String,
+    /// Seconds until the refresh token expires if it is not used
+    pub refresh_expires_in: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IdentitiesResponse {
+    pub status: String,
+    pub message: String,
+    pub identities: Vec<ExternalIdentity>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuthorizationUrlResponse {
+    pub status: String,
+    pub message: String,
+    pub authorization_url: String,
 }
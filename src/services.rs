@@ -1,2 +1,5 @@
+pub mod admin;
+pub mod api_token;
 pub mod auth;
+pub mod oidc;
 pub mod user;
@@ -0,0 +1,234 @@
+use crate::{
+    actors::{login_throttle::LoginThrottle, session::SessionIndex},
+    errors::AppError,
+    extractors::{authenticated_user::AuthenticatedUser, validated_json::ValidatedJson},
+    mailer::Mailer,
+    middleware::require_permission::RequirePermission,
+    models::{
+        lockout_model::LockoutKind,
+        role_model::Permission,
+        user_model::{AdminUpdateUser, User, UserChanges, UserListQuery},
+    },
+    response::{GenericResponse, LockoutsResponse, UserResponse, UsersResponse},
+    services::user::send_verification_email,
+    utils::{
+        config::{with_database_connection, AuthConfig, MailConfig},
+        email::normalize_email,
+        helpers::DbPool,
+        redis::RedisAddr,
+        tokens::TokenSigner,
+    },
+};
+use actix_web::{delete, get, patch, web, HttpResponse};
+use chrono::Utc;
+use uuid::Uuid;
+use validator::Validate;
+
+#[get("/admin/users", wrap = "RequirePermission(Permission::ViewUsers)")]
+async fn list_users(
+    pool: web::Data<DbPool>,
+    query: web::Query<UserListQuery>,
+) -> Result<HttpResponse, AppError> {
+    // Lists accounts page by page, newest first unless `order=asc` is given.
+    //
+    // # Parameters
+    //
+    // * `query`: `page`, `per_page`, `search` and `order` query string parameters.
+    //
+    query.validate()?;
+    let query = query.into_inner();
+    let (page, per_page) = (query.page, query.per_page);
+    let (users, total) =
+        with_database_connection(&pool, move |conn| User::list_users(conn, &query)).await?;
+
+    Ok(HttpResponse::Ok().json(UsersResponse {
+        status: "success".to_string(),
+        message: "Users".to_string(),
+        users: users.into_iter().map(Into::into).collect(),
+        page,
+        per_page,
+        total,
+    }))
+}
+
+#[get("/admin/users/{id}", wrap = "RequirePermission(Permission::ViewUsers)")]
+async fn get_user(
+    pool: web::Data<DbPool>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = path.into_inner();
+    let user =
+        with_database_connection(&pool, move |conn| User::find_user_by_id(conn, user_id)).await?;
+
+    Ok(HttpResponse::Ok().json(UserResponse {
+        status: "success".to_string(),
+        message: "User".to_string(),
+        user: user.into(),
+    }))
+}
+
+#[patch(
+    "/admin/users/{id}",
+    wrap = "RequirePermission(Permission::ManageUsers)"
+)]
+#[allow(clippy::too_many_arguments)]
+async fn update_user(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    signer: web::Data<TokenSigner>,
+    mailer: web::Data<dyn Mailer>,
+    auth_config: web::Data<AuthConfig>,
+    mail_config: web::Data<MailConfig>,
+    admin: AuthenticatedUser,
+    path: web::Path<Uuid>,
+    form: ValidatedJson<AdminUpdateUser>,
+) -> Result<HttpResponse, AppError> {
+    // Updates the name, email, role or suspension of another account.
+    //
+    // Suspending an account also logs it out everywhere. A new email address is marked
+    // unverified and a verification link is sent to it.
+    //
+    // # Errors
+    //
+    // An `AppError::Forbidden` is returned when admins try to change their own role or
+    // suspend themselves, or change a role without `Permission::ManageRoles`.
+    // An `AppError::Conflict` is returned if the new email is already taken.
+    //
+    let user_id = path.into_inner();
+    let form = form.into_inner();
+
+    if user_id == admin.id && (form.role.is_some() || form.suspended.is_some()) {
+        return Err(AppError::Forbidden(
+            "You cannot change your own role or suspension".to_string(),
+        ));
+    }
+    if form.role.is_some() {
+        admin.require(Permission::ManageRoles)?;
+    }
+
+    let updated_user = with_database_connection(&pool, move |conn| {
+        let user = User::find_user_by_id(conn, user_id)?;
+
+        let new_email = form
+            .email
+            .map(|email| normalize_email(&email, auth_config.lowercase_email_local_part))
+            .filter(|email| *email != user.email);
+        let email_changed = new_email.is_some();
+
+        let suspended_at = form.suspended.map(|suspended| {
+            if suspended {
+                Some(user.suspended_at.unwrap_or_else(|| Utc::now().naive_utc()))
+            } else {
+                None
+            }
+        });
+        let updated_user = User::update_user(
+            conn,
+            user_id,
+            &UserChanges {
+                full_name: form.full_name,
+                email: new_email,
+                role: form.role,
+                email_verified_at: email_changed.then_some(None),
+                suspended_at,
+                ..Default::default()
+            },
+        )?;
+
+        if email_changed {
+            send_verification_email(
+                conn,
+                &signer,
+                mailer.get_ref(),
+                &auth_config,
+                &mail_config,
+                &updated_user,
+            )?;
+        }
+
+        Ok(updated_user)
+    })
+    .await?;
+
+    if updated_user.suspended_at.is_some() {
+        SessionIndex::revoke_all(&redis, user_id).await?;
+    }
+
+    Ok(HttpResponse::Ok().json(UserResponse {
+        status: "success".to_string(),
+        message: "User updated".to_string(),
+        user: updated_user.into(),
+    }))
+}
+
+#[delete(
+    "/admin/users/{id}",
+    wrap = "RequirePermission(Permission::ManageUsers)"
+)]
+async fn delete_user(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    admin: AuthenticatedUser,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    // Deletes another account and logs it out everywhere.
+    //
+    // Admins delete their own account through `DELETE /user/me` instead.
+    //
+    let user_id = path.into_inner();
+    if user_id == admin.id {
+        return Err(AppError::Forbidden(
+            "Use DELETE /user/me to delete your own account".to_string(),
+        ));
+    }
+
+    with_database_connection(&pool, move |conn| User::delete_user(conn, user_id)).await?;
+    SessionIndex::revoke_all(&redis, user_id).await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "User deleted".to_string(),
+    }))
+}
+
+#[get("/admin/lockouts", wrap = "RequirePermission(Permission::ViewUsers)")]
+async fn list_lockouts(redis: web::Data<RedisAddr>) -> Result<HttpResponse, AppError> {
+    // Lists the accounts and client IPs currently locked out after too many failed logins.
+    //
+    let lockouts = LoginThrottle::lockouts(&redis).await?;
+
+    Ok(HttpResponse::Ok().json(LockoutsResponse {
+        status: "success".to_string(),
+        message: "Active lockouts".to_string(),
+        lockouts,
+    }))
+}
+
+#[delete(
+    "/admin/users/{id}/lockout",
+    wrap = "RequirePermission(Permission::ManageUsers)"
+)]
+async fn clear_user_lockout(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    // Lifts the login lockout of an account before it expires.
+    //
+    // # Errors
+    //
+    // An `AppError::NotFound` is returned if the account is not locked out.
+    //
+    let user_id = path.into_inner();
+    let user =
+        with_database_connection(&pool, move |conn| User::find_user_by_id(conn, user_id)).await?;
+
+    if !LoginThrottle::clear(&redis, LockoutKind::Account, &user.email.to_lowercase()).await? {
+        return Err(AppError::NotFound("Account is not locked out".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Lockout cleared".to_string(),
+    }))
+}
@@ -0,0 +1,88 @@
+use crate::{
+    errors::AppError,
+    extractors::{authenticated_user::AuthenticatedUser, validated_json::ValidatedJson},
+    models::api_token_model::{ApiToken, CreateApiToken},
+    response::{ApiTokenCreatedResponse, ApiTokensResponse, GenericResponse},
+    utils::{config::with_database_connection, helpers::DbPool, tokens::TokenSigner},
+};
+use actix_web::{delete, get, post, web, HttpResponse};
+use uuid::Uuid;
+
+#[post("/user/me/tokens")]
+async fn create_token(
+    pool: web::Data<DbPool>,
+    signer: web::Data<TokenSigner>,
+    user: AuthenticatedUser,
+    form: ValidatedJson<CreateApiToken>,
+) -> Result<HttpResponse, AppError> {
+    // Creates a personal API token for the current user.
+    //
+    // Tokens can only be managed from a session or an access token, never with another API token.
+    //
+    // # Returns
+    //
+    // The token details and the token itself, which is not stored and cannot be shown again.
+    //
+    user.reject_api_token()?;
+    let (user_id, form) = (user.id, form.into_inner());
+    let (token, secret) = with_database_connection(&pool, move |conn| {
+        ApiToken::create(conn, &signer, user_id, form)
+    })
+    .await?;
+
+    Ok(HttpResponse::Created().json(ApiTokenCreatedResponse {
+        status: "success".to_string(),
+        message: "API token created".to_string(),
+        token: token.into(),
+        secret,
+    }))
+}
+
+#[get("/user/me/tokens")]
+async fn list_tokens(
+    pool: web::Data<DbPool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    // Lists the personal API tokens of the current user, including expired ones.
+    //
+    user.reject_api_token()?;
+    let user_id = user.id;
+    let tokens = with_database_connection(&pool, move |conn| ApiToken::list(conn, user_id))
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ApiTokensResponse {
+        status: "success".to_string(),
+        message: "API tokens".to_string(),
+        tokens,
+    }))
+}
+
+#[delete("/user/me/tokens/{id}")]
+async fn revoke_token(
+    pool: web::Data<DbPool>,
+    user: AuthenticatedUser,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    // Revokes one of the current user's API tokens, effective immediately.
+    //
+    // # Errors
+    //
+    // An `AppError::NotFound` is returned if the user has no token with that id.
+    //
+    user.reject_api_token()?;
+    let (user_id, token_id) = (user.id, path.into_inner());
+
+    if !with_database_connection(&pool, move |conn| ApiToken::revoke(conn, user_id, token_id))
+        .await?
+    {
+        return Err(AppError::NotFound("API token not found".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "API token revoked".to_string(),
+    }))
+}
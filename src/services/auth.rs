@@ -1,31 +1,449 @@
 use crate::{
     actors::auth::Auth,
-    models::user_model::AuthCredentials,
-    utils::{config::get_database_connection, helpers::DbPool},
+    actors::login_throttle::LoginThrottle,
+    actors::session::SessionIndex,
+    actors::two_factor::TwoFactor,
+    errors::AppError,
+    extractors::{authenticated_user::AuthenticatedUser, validated_json::ValidatedJson},
+    mailer::{Email, Mailer},
+    models::{
+        password_reset_model::{ForgotPassword, PasswordResetToken, ResetPassword},
+        refresh_token_model::{RefreshToken, RefreshTokenRequest, TokenRequest},
+        two_factor_model::CompleteTwoFactorLogin,
+        user_model::{AuthCredentials, User},
+    },
+    response::{GenericResponse, TokenResponse, TwoFactorRequiredResponse},
+    utils::{
+        config::{
+            with_database_connection, AuthConfig, JwtConfig, LoginThrottleConfig, MailConfig,
+            SessionConfig, TwoFactorConfig,
+        },
+        helpers::{client_ip, DbPool},
+        jwt::JwtSigner,
+        password::PasswordHasher,
+        redis::RedisAddr,
+        tokens::TokenSigner,
+    },
 };
 use actix_session::Session;
-use actix_web::{post, web, HttpResponse, Responder, Result};
-use serde_json::json;
-use validator::Validate;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use chrono::Duration;
 
+#[allow(clippy::too_many_arguments)]
 #[post("/auth/login")]
 async fn login(
     pool: web::Data<DbPool>,
-    form: web::Json<AuthCredentials>,
+    redis: web::Data<RedisAddr>,
+    session_config: web::Data<SessionConfig>,
+    signer: web::Data<TokenSigner>,
+    hasher: web::Data<PasswordHasher>,
+    auth_config: web::Data<AuthConfig>,
+    throttle_config: web::Data<LoginThrottleConfig>,
+    two_factor_config: web::Data<TwoFactorConfig>,
+    form: ValidatedJson<AuthCredentials>,
     session: Session,
-) -> Result<impl Responder> {
-    let mut conn = get_database_connection(&pool)?;
-
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    // Logs the user in with their email and password.
+    //
+    // Accounts with two-factor authentication are not logged in yet: the response has the
+    // status "2fa_required" and a pending login token to complete at `/auth/login/2fa`.
+    //
+    // # Errors
+    //
+    // An `AppError::TooManyRequests` is returned while the account or the client IP
+    // is locked out after too many failed attempts.
+    //
     let user_credentials = form.into_inner();
-    if user_credentials.validate().is_ok() {
-        match Auth::credentials(&mut conn, &user_credentials) {
-            Ok(user) => {
-                let _ = session.insert("user_email", user);
-                Ok(HttpResponse::Ok().json(json!({ "status": "OK", "message": "User logged"})))
+    let ip = client_ip(&req, throttle_config.trust_proxy_headers);
+    let throttled_email = user_credentials.email.to_lowercase();
+    LoginThrottle::check(&redis, &throttle_config, &ip, &throttled_email).await?;
+
+    let require_verified_email = auth_config.require_verified_email;
+    let user = match with_database_connection(&pool, move |conn| {
+        Auth::credentials(conn, &hasher, &user_credentials, require_verified_email)
+    })
+    .await
+    {
+        Ok(user) => user,
+        Err(AppError::Unauthorized(message)) => {
+            LoginThrottle::record_failure(&redis, &throttle_config, &ip, &throttled_email).await?;
+            return Err(AppError::Unauthorized(message));
+        }
+        Err(err) => return Err(err),
+    };
+
+    if user.two_factor_enabled() {
+        // Failed attempts keep counting until the second step succeeds
+        let token =
+            TwoFactor::issue_login_token(&redis, &signer, &two_factor_config, user.id).await?;
+        return Ok(HttpResponse::Ok().json(TwoFactorRequiredResponse {
+            status: "2fa_required".to_string(),
+            message: "Two-factor authentication code required".to_string(),
+            token,
+            expires_in: two_factor_config.login_token_ttl_seconds,
+        }));
+    }
+
+    LoginThrottle::record_success(&redis, &throttled_email).await?;
+    Auth::start_session(
+        &session,
+        &redis,
+        &session_config,
+        &req,
+        throttle_config.trust_proxy_headers,
+        user.id,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "OK".to_string(),
+        message: "User logged".to_string(),
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/auth/login/2fa")]
+async fn complete_two_factor_login(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    session_config: web::Data<SessionConfig>,
+    signer: web::Data<TokenSigner>,
+    throttle_config: web::Data<LoginThrottleConfig>,
+    two_factor_config: web::Data<TwoFactorConfig>,
+    form: ValidatedJson<CompleteTwoFactorLogin>,
+    session: Session,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    // Completes a login started at `/auth/login` with a TOTP code or a recovery code.
+    //
+    // Wrong codes count as failed logins for the account and the client IP, so the
+    // code cannot be brute forced while the pending login token is valid.
+    //
+    // # Errors
+    //
+    // An `AppError::InvalidToken` is returned if the pending login token is unknown or has expired,
+    // an `AppError::Unauthorized` if the code is wrong.
+    //
+    let user_id = TwoFactor::login_token_user(&redis, &signer, &form.token).await?;
+    let user = with_database_connection(&pool, move |conn| User::find_user_by_id(conn, user_id))
+        .await
+        .map_err(|err| match err {
+            AppError::NotFound(_) => {
+                AppError::InvalidToken("Invalid or expired login token".to_string())
             }
-            Err(_) => Err(actix_web::error::ErrorUnauthorized("Unauthorized!")),
+            err => err,
+        })?;
+    if user.suspended_at.is_some() {
+        return Err(AppError::Forbidden("Account is suspended".to_string()));
+    }
+
+    let ip = client_ip(&req, throttle_config.trust_proxy_headers);
+    let throttled_email = user.email.to_lowercase();
+    LoginThrottle::check(&redis, &throttle_config, &ip, &throttled_email).await?;
+
+    let verified = {
+        let (signer, two_factor_config) = (signer.clone(), two_factor_config.clone());
+        let (user, code) = (user.clone(), form.code.clone());
+        with_database_connection(&pool, move |conn| {
+            TwoFactor::verify(conn, &signer, &two_factor_config, &user, &code)
+        })
+        .await?
+    };
+    if !verified {
+        LoginThrottle::record_failure(&redis, &throttle_config, &ip, &throttled_email).await?;
+        return Err(AppError::Unauthorized(
+            "Invalid two-factor authentication code".to_string(),
+        ));
+    }
+
+    TwoFactor::consume_login_token(&redis, &signer, &form.token).await?;
+    LoginThrottle::record_success(&redis, &throttled_email).await?;
+    Auth::start_session(
+        &session,
+        &redis,
+        &session_config,
+        &req,
+        throttle_config.trust_proxy_headers,
+        user.id,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "OK".to_string(),
+        message: "User logged".to_string(),
+    }))
+}
+
+#[post("/auth/password/forgot")]
+async fn forgot_password(
+    pool: web::Data<DbPool>,
+    signer: web::Data<TokenSigner>,
+    mailer: web::Data<dyn Mailer>,
+    auth_config: web::Data<AuthConfig>,
+    mail_config: web::Data<MailConfig>,
+    form: ValidatedJson<ForgotPassword>,
+) -> Result<HttpResponse, AppError> {
+    // Emails a password reset link to the account owner.
+    //
+    // The response is identical whether or not the email is registered,
+    // so this endpoint cannot be used to discover accounts.
+    //
+    let form = form.into_inner();
+    with_database_connection(&pool, move |conn| {
+        if let Ok(user) = User::find_user_by_email(conn, &form.email) {
+            let token = PasswordResetToken::issue(
+                conn,
+                &signer,
+                user.id,
+                Duration::minutes(auth_config.password_reset_token_ttl_minutes),
+            )?;
+            let link = format!(
+                "{}/reset-password?token={}",
+                mail_config.frontend_url, token
+            );
+            mailer.send(&Email::password_reset(&user.email, &link))?;
+        }
+
+        Ok(())
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "If the account exists, a password reset email has been sent".to_string(),
+    }))
+}
+
+#[post("/auth/password/reset")]
+async fn reset_password(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    signer: web::Data<TokenSigner>,
+    hasher: web::Data<PasswordHasher>,
+    jwt_config: web::Data<JwtConfig>,
+    form: ValidatedJson<ResetPassword>,
+) -> Result<HttpResponse, AppError> {
+    // Sets a new password using a token from `/auth/password/forgot`
+    // and logs the user out of every existing session.
+    //
+    // # Errors
+    //
+    // An `AppError::InvalidToken` is returned if the token is unknown, was already used or has expired.
+    //
+    let form = form.into_inner();
+    let user = with_database_connection(&pool, move |conn| {
+        PasswordResetToken::consume(conn, &signer, &hasher, &form.token, &form.password)
+    })
+    .await?;
+    SessionIndex::revoke_all(&redis, user.id).await?;
+    RefreshToken::revoke_all(&pool, &redis, &jwt_config, user.id).await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Password has been reset".to_string(),
+    }))
+}
+
+#[post("/auth/logout")]
+async fn logout(
+    redis: web::Data<RedisAddr>,
+    session: Session,
+    _user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    // Ends the current session.
+    //
+    Auth::end_session(&session, &redis).await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Logged out".to_string(),
+    }))
+}
+
+#[post("/auth/logout-all")]
+async fn logout_all(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    jwt_config: web::Data<JwtConfig>,
+    session: Session,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    // Ends every session of the current user, on all devices, and revokes their JWT refresh tokens.
+    //
+    SessionIndex::revoke_all(&redis, user.id).await?;
+    RefreshToken::revoke_all(&pool, &redis, &jwt_config, user.id).await?;
+    session.purge();
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Logged out of all sessions".to_string(),
+    }))
+}
+
+/// Access and refresh token pair for `user_id`, continuing `family` when rotating
+pub(crate) fn token_response(
+    jwt_signer: &JwtSigner,
+    jwt_config: &JwtConfig,
+    user_id: uuid::Uuid,
+    family: uuid::Uuid,
+    refresh_token: String,
+) -> Result<TokenResponse, AppError> {
+    let access_token = jwt_signer.issue(
+        user_id,
+        family,
+        Duration::seconds(jwt_config.access_token_ttl_seconds),
+    )?;
+
+    Ok(TokenResponse {
+        status: "success".to_string(),
+        message: "Tokens issued".to_string(),
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: jwt_config.access_token_ttl_seconds,
+        refresh_token,
+        refresh_expires_in: jwt_config.refresh_token_ttl_days * 24 * 60 * 60,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/auth/token")]
+async fn issue_token(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    signer: web::Data<TokenSigner>,
+    jwt_signer: web::Data<JwtSigner>,
+    hasher: web::Data<PasswordHasher>,
+    auth_config: web::Data<AuthConfig>,
+    throttle_config: web::Data<LoginThrottleConfig>,
+    jwt_config: web::Data<JwtConfig>,
+    two_factor_config: web::Data<TwoFactorConfig>,
+    form: ValidatedJson<TokenRequest>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    // Logs the user in without a cookie session, for mobile clients.
+    //
+    // Returns a short-lived JWT access token to send as `Authorization: Bearer`
+    // and a refresh token to exchange for new tokens at `/auth/token/refresh`.
+    // Accounts with two-factor authentication must send a `code` along with the password.
+    //
+    // # Errors
+    //
+    // An `AppError::TwoFactorRequired` is returned if the account needs a code and none was sent,
+    // an `AppError::TooManyRequests` while the account or the client IP is locked out.
+    //
+    let form = form.into_inner();
+    let ip = client_ip(&req, throttle_config.trust_proxy_headers);
+    let throttled_email = form.email.to_lowercase();
+    LoginThrottle::check(&redis, &throttle_config, &ip, &throttled_email).await?;
+
+    let credentials = AuthCredentials {
+        email: form.email,
+        password: form.password,
+    };
+    let require_verified_email = auth_config.require_verified_email;
+    let user = match with_database_connection(&pool, move |conn| {
+        Auth::credentials(conn, &hasher, &credentials, require_verified_email)
+    })
+    .await
+    {
+        Ok(user) => user,
+        Err(AppError::Unauthorized(message)) => {
+            LoginThrottle::record_failure(&redis, &throttle_config, &ip, &throttled_email).await?;
+            return Err(AppError::Unauthorized(message));
+        }
+        Err(err) => return Err(err),
+    };
+
+    if user.two_factor_enabled() {
+        let code = form.code.ok_or(AppError::TwoFactorRequired)?;
+        let (signer, user) = (signer.clone(), user.clone());
+        let verified = with_database_connection(&pool, move |conn| {
+            TwoFactor::verify(conn, &signer, &two_factor_config, &user, &code)
+        })
+        .await?;
+        if !verified {
+            LoginThrottle::record_failure(&redis, &throttle_config, &ip, &throttled_email).await?;
+            return Err(AppError::Unauthorized(
+                "Invalid two-factor authentication code".to_string(),
+            ));
+        }
+    }
+
+    LoginThrottle::record_success(&redis, &throttled_email).await?;
+    let (family, refresh_token) = {
+        let (signer, jwt_config, user_id) = (signer.clone(), jwt_config.clone(), user.id);
+        with_database_connection(&pool, move |conn| {
+            RefreshToken::issue(conn, &signer, &jwt_config, user_id, None)
+        })
+        .await?
+    };
+
+    Ok(HttpResponse::Ok().json(token_response(
+        &jwt_signer,
+        &jwt_config,
+        user.id,
+        family,
+        refresh_token,
+    )?))
+}
+
+#[post("/auth/token/refresh")]
+async fn refresh_access_token(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    signer: web::Data<TokenSigner>,
+    jwt_signer: web::Data<JwtSigner>,
+    jwt_config: web::Data<JwtConfig>,
+    form: ValidatedJson<RefreshTokenRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Exchanges a refresh token for a new access token and a new refresh token.
+    //
+    // The old refresh token stops working. Presenting it again revokes every token
+    // descending from the same login, since it can only mean the token was stolen.
+    //
+    // # Errors
+    //
+    // An `AppError::InvalidToken` is returned if the refresh token is unknown, expired, revoked or reused.
+    //
+    let (user_id, family, refresh_token) =
+        RefreshToken::rotate(&pool, &redis, &signer, &jwt_config, &form.refresh_token).await?;
+
+    // Suspended and deleted accounts cannot mint new access tokens
+    match with_database_connection(&pool, move |conn| User::find_user_by_id(conn, user_id)).await {
+        Ok(user) if user.suspended_at.is_none() => {}
+        Ok(_) | Err(AppError::NotFound(_)) => {
+            RefreshToken::revoke_family(&pool, &redis, &jwt_config, family).await?;
+            return Err(AppError::InvalidToken(
+                "Invalid or expired refresh token".to_string(),
+            ));
         }
-    } else {
-        Ok(HttpResponse::BadRequest().json("Invalid login credentials"))
+        Err(err) => return Err(err),
     }
+
+    Ok(HttpResponse::Ok().json(token_response(
+        &jwt_signer,
+        &jwt_config,
+        user_id,
+        family,
+        refresh_token,
+    )?))
+}
+
+#[post("/auth/token/revoke")]
+async fn revoke_refresh_token(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    signer: web::Data<TokenSigner>,
+    jwt_config: web::Data<JwtConfig>,
+    form: ValidatedJson<RefreshTokenRequest>,
+) -> Result<HttpResponse, AppError> {
+    // Logs a token client out by revoking its refresh token and the access tokens issued with it.
+    //
+    // Unknown tokens are accepted silently, so clients can always discard their tokens afterwards.
+    //
+    RefreshToken::revoke(&pool, &redis, &signer, &jwt_config, &form.refresh_token).await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Token revoked".to_string(),
+    }))
 }
@@ -0,0 +1,373 @@
+use crate::{
+    actors::{auth::Auth, two_factor::TwoFactor},
+    errors::AppError,
+    extractors::authenticated_user::AuthenticatedUser,
+    models::{
+        identity_model::{ExternalIdentity, OidcAuthorization, OidcCallback, OidcUserInfo},
+        user_model::{CreateUser, User},
+    },
+    response::{
+        AuthorizationUrlResponse, GenericResponse, IdentitiesResponse, TwoFactorRequiredResponse,
+    },
+    utils::{
+        config::{
+            with_database_connection, AuthConfig, LoginThrottleConfig, OidcConfig, SessionConfig,
+            TwoFactorConfig,
+        },
+        email::normalize_email,
+        helpers::DbPool,
+        oidc::{authorization_url, exchange_code, fetch_userinfo, random_token, verify_id_token},
+        password::PasswordHasher,
+        redis::RedisAddr,
+        tokens::TokenSigner,
+    },
+};
+use actix_session::Session;
+use actix_web::{delete, get, http::header, post, web, HttpRequest, HttpResponse};
+use diesel::{Connection, PgConnection};
+
+/// Session key binding a sign in to the browser that started it, against login CSRF
+const OIDC_STATE_SESSION_KEY: &str = "oidc_state";
+
+/// Stores a new sign in and returns the provider URL to send the browser to
+async fn begin_authorization(
+    redis: &RedisAddr,
+    session: &Session,
+    oidc_config: &OidcConfig,
+    provider: &str,
+    link_user_id: Option<uuid::Uuid>,
+) -> Result<String, AppError> {
+    let provider_config = oidc_config.provider(provider)?;
+    let authorization = OidcAuthorization {
+        provider: provider.to_string(),
+        code_verifier: random_token(),
+        nonce: random_token(),
+        link_user_id,
+    };
+    let state = authorization
+        .store(redis, oidc_config.state_ttl_seconds)
+        .await?;
+    session
+        .insert(OIDC_STATE_SESSION_KEY, &state)
+        .map_err(|err| AppError::Internal(format!("Failed to write session: {}", err)))?;
+
+    authorization_url(
+        provider_config,
+        &state,
+        &authorization.code_verifier,
+        &authorization.nonce,
+    )
+}
+
+/// Creates the user signing in with a provider account that is not linked yet
+fn create_user_from_identity(
+    conn: &mut PgConnection,
+    hasher: &PasswordHasher,
+    auth_config: &AuthConfig,
+    provider: &str,
+    issuer: &str,
+    info: &OidcUserInfo,
+) -> Result<User, AppError> {
+    let email = info.email.as_deref().ok_or_else(|| {
+        AppError::BadRequest("The identity provider did not share an email address".to_string())
+    })?;
+    // Otherwise anyone could sign up at the provider with someone else's address and keep
+    // a way into the account after its real owner claims it with a password reset
+    if !info.email_verified {
+        return Err(AppError::Forbidden(
+            "The identity provider has not verified your email address".to_string(),
+        ));
+    }
+    let email = normalize_email(email, auth_config.lowercase_email_local_part);
+
+    // Linking to an existing account by email alone would let anyone who controls
+    // that address at the provider take the account over
+    if User::find_user_by_email(conn, &email).is_ok() {
+        return Err(AppError::Conflict(
+            "An account with this email already exists, log in and link the provider from your profile"
+                .to_string(),
+        ));
+    }
+
+    let full_name = info
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default())
+        .chars()
+        .take(255)
+        .collect();
+
+    conn.transaction(|conn| {
+        // The account has no usable password until the user resets it
+        let user = User::add_user(
+            conn,
+            hasher,
+            CreateUser {
+                full_name,
+                email,
+                password: random_token(),
+            },
+        )?;
+        User::mark_email_verified(conn, user.id)?;
+        ExternalIdentity::link(conn, user.id, provider, issuer, info)?;
+
+        User::find_user_by_id(conn, user.id)
+    })
+}
+
+#[get("/auth/oidc/{provider}/login")]
+async fn oidc_login(
+    redis: web::Data<RedisAddr>,
+    oidc_config: web::Data<OidcConfig>,
+    session: Session,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    // Starts signing in with an external identity provider, redirecting the browser to it.
+    //
+    // Uses the authorization code flow with PKCE; the provider redirects back to
+    // `/auth/oidc/{provider}/callback`.
+    //
+    // # Errors
+    //
+    // An `AppError::NotFound` is returned if the provider is not configured.
+    //
+    let url = begin_authorization(&redis, &session, &oidc_config, &path, None).await?;
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .finish())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/auth/oidc/{provider}/callback")]
+async fn oidc_callback(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    session_config: web::Data<SessionConfig>,
+    signer: web::Data<TokenSigner>,
+    http_client: web::Data<reqwest::Client>,
+    hasher: web::Data<PasswordHasher>,
+    auth_config: web::Data<AuthConfig>,
+    oidc_config: web::Data<OidcConfig>,
+    two_factor_config: web::Data<TwoFactorConfig>,
+    throttle_config: web::Data<LoginThrottleConfig>,
+    session: Session,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<OidcCallback>,
+) -> Result<HttpResponse, AppError> {
+    // Completes a sign in or a provider link started by this browser.
+    //
+    // Signing in with an unlinked provider account creates a new user, unless its email
+    // already belongs to an account or the provider has not verified it. Accounts with two-factor authentication get the same
+    // "2fa_required" response as `/auth/login`.
+    //
+    // # Errors
+    //
+    // An `AppError::InvalidToken` is returned if the state is unknown, expired or was issued to
+    // another browser, an `AppError::Unauthorized` if the provider refused the sign in or its
+    // ID token does not match the configured issuer, the client or this sign in's nonce.
+    //
+    let provider = path.into_inner();
+    let query = query.into_inner();
+    if let Some(error) = query.error {
+        return Err(AppError::Unauthorized(format!(
+            "The identity provider refused the sign in: {}",
+            error
+        )));
+    }
+    let (code, state) = query
+        .code
+        .zip(query.state)
+        .ok_or_else(|| AppError::BadRequest("Missing code or state in the callback".to_string()))?;
+
+    let session_state: Option<String> = session
+        .remove_as(OIDC_STATE_SESSION_KEY)
+        .and_then(Result::ok);
+    if session_state.as_deref() != Some(state.as_str()) {
+        return Err(AppError::InvalidToken(
+            "Invalid or expired sign in state".to_string(),
+        ));
+    }
+    let authorization = OidcAuthorization::take(&redis, &state).await?;
+    if authorization.provider != provider {
+        return Err(AppError::InvalidToken(
+            "Invalid or expired sign in state".to_string(),
+        ));
+    }
+
+    let provider_config = oidc_config.provider(&provider)?;
+    let tokens = exchange_code(
+        &http_client,
+        provider_config,
+        &code,
+        &authorization.code_verifier,
+    )
+    .await?;
+    let claims = verify_id_token(provider_config, &tokens.id_token, &authorization.nonce)?;
+    let info = fetch_userinfo(&http_client, provider_config, &tokens.access_token).await?;
+    if info.sub != claims.sub {
+        return Err(AppError::Unauthorized(
+            "The identity provider returned claims for another account".to_string(),
+        ));
+    }
+    let issuer = claims.iss;
+
+    if let Some(user_id) = authorization.link_user_id {
+        // The browser must still be logged in as the user who asked for the link
+        let current_user = Auth::validate_session(&session, &redis, &session_config).await?;
+        if current_user != user_id {
+            return Err(AppError::Forbidden(
+                "The provider link was started by another user".to_string(),
+            ));
+        }
+        with_database_connection(&pool, move |conn| {
+            ExternalIdentity::link(conn, user_id, &provider, &issuer, &info)
+        })
+        .await?;
+
+        return Ok(HttpResponse::Ok().json(GenericResponse {
+            status: "success".to_string(),
+            message: "Identity provider linked".to_string(),
+        }));
+    }
+
+    let user = {
+        let auth_config = auth_config.clone();
+        with_database_connection(&pool, move |conn| {
+            match ExternalIdentity::find(conn, &provider, &issuer, &info.sub)? {
+                Some(identity) => {
+                    identity.record_login(conn, &issuer, &info)?;
+                    User::find_user_by_id(conn, identity.user_id)
+                }
+                None => create_user_from_identity(
+                    conn,
+                    &hasher,
+                    &auth_config,
+                    &provider,
+                    &issuer,
+                    &info,
+                ),
+            }
+        })
+        .await?
+    };
+
+    if user.suspended_at.is_some() {
+        return Err(AppError::Forbidden("Account is suspended".to_string()));
+    }
+    if auth_config.require_verified_email && user.email_verified_at.is_none() {
+        return Err(AppError::EmailNotVerified);
+    }
+
+    if user.two_factor_enabled() {
+        let token =
+            TwoFactor::issue_login_token(&redis, &signer, &two_factor_config, user.id).await?;
+        return Ok(HttpResponse::Ok().json(TwoFactorRequiredResponse {
+            status: "2fa_required".to_string(),
+            message: "Two-factor authentication code required".to_string(),
+            token,
+            expires_in: two_factor_config.login_token_ttl_seconds,
+        }));
+    }
+
+    Auth::start_session(
+        &session,
+        &redis,
+        &session_config,
+        &req,
+        throttle_config.trust_proxy_headers,
+        user.id,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "OK".to_string(),
+        message: "User logged".to_string(),
+    }))
+}
+
+#[get("/user/me/identities")]
+async fn list_identities(
+    pool: web::Data<DbPool>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    // Lists the external identity providers linked to the current user.
+    //
+    let user_id = user.id;
+    let identities =
+        with_database_connection(&pool, move |conn| ExternalIdentity::list(conn, user_id)).await?;
+
+    Ok(HttpResponse::Ok().json(IdentitiesResponse {
+        status: "success".to_string(),
+        message: "Linked identities".to_string(),
+        identities,
+    }))
+}
+
+#[post("/user/me/identities/{provider}")]
+async fn link_identity(
+    redis: web::Data<RedisAddr>,
+    oidc_config: web::Data<OidcConfig>,
+    session: Session,
+    user: AuthenticatedUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    // Starts linking an external identity provider to the current user.
+    //
+    // # Returns
+    //
+    // The provider URL to open in this browser; the link is made when it redirects back
+    // to `/auth/oidc/{provider}/callback` while the user is still logged in.
+    //
+    user.reject_api_token()?;
+    let authorization_url =
+        begin_authorization(&redis, &session, &oidc_config, &path, Some(user.id)).await?;
+
+    Ok(HttpResponse::Ok().json(AuthorizationUrlResponse {
+        status: "success".to_string(),
+        message: "Open the URL to link the identity provider".to_string(),
+        authorization_url,
+    }))
+}
+
+#[delete("/user/me/identities/{provider}")]
+async fn unlink_identity(
+    pool: web::Data<DbPool>,
+    user: AuthenticatedUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    // Unlinks an external identity provider from the current user.
+    //
+    // # Errors
+    //
+    // An `AppError::BadRequest` is returned when unlinking the last provider of an account
+    // whose email is unverified, since the user could then no longer reset their password.
+    //
+    user.reject_api_token()?;
+    let provider = path.into_inner();
+
+    with_database_connection(&pool, move |conn| {
+        let identities = ExternalIdentity::list(conn, user.id)?;
+        if identities.len() == 1 && user.email_verified_at.is_none() {
+            return Err(AppError::BadRequest(
+                "Verify your email address before unlinking your last identity provider"
+                    .to_string(),
+            ));
+        }
+        if !ExternalIdentity::unlink(conn, user.id, &provider)? {
+            return Err(AppError::NotFound(
+                "Identity provider not linked".to_string(),
+            ));
+        }
+
+        Ok(())
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Identity provider unlinked".to_string(),
+    }))
+}
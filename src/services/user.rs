@@ -1,18 +1,86 @@
 use crate::{
-    actors::auth::Auth,
-    models::user_model::{CreateUser, User},
-    utils::helpers::DbPool,
+    actors::{auth::Auth, session::SessionIndex, two_factor::TwoFactor},
+    errors::AppError,
+    extractors::{
+        authenticated_user::{AuthMethod, AuthenticatedUser},
+        validated_json::ValidatedJson,
+    },
+    mailer::{Email, Mailer},
+    models::{
+        refresh_token_model::RefreshToken,
+        two_factor_model::{DisableTwoFactor, TwoFactorCode},
+        user_model::{ChangePassword, CreateUser, UpdateProfile, User, UserChanges},
+        verification_model::{EmailVerificationToken, ResendVerification, VerifyEmail},
+    },
+    response::{
+        ActiveSession, GenericResponse, PermissionsResponse, ProfileResponse,
+        RecoveryCodesResponse, SessionsResponse, TwoFactorSetupResponse,
+    },
+    services::auth::token_response,
+    utils::{
+        config::{
+            with_database_connection, AuthConfig, JwtConfig, LoginThrottleConfig, MailConfig,
+            SessionConfig, TwoFactorConfig,
+        },
+        email::normalize_email,
+        helpers::DbPool,
+        jwt::JwtSigner,
+        password::PasswordHasher,
+        redis::RedisAddr,
+        tokens::TokenSigner,
+    },
 };
 use actix_session::Session;
-use actix_web::{delete, post, web, HttpResponse, Responder, Result};
-use serde_json::json;
+use actix_web::{delete, get, patch, post, put, web, HttpRequest, HttpResponse};
+use chrono::Duration;
+use diesel::{Connection, PgConnection};
+
+/// Issues a fresh verification token for `user` and returns the link to verify it with
+fn verification_link(
+    conn: &mut PgConnection,
+    signer: &TokenSigner,
+    auth_config: &AuthConfig,
+    mail_config: &MailConfig,
+    user: &User,
+) -> Result<String, AppError> {
+    let token = EmailVerificationToken::issue(
+        conn,
+        signer,
+        user.id,
+        Duration::minutes(auth_config.verification_token_ttl_minutes),
+    )?;
+
+    Ok(format!(
+        "{}/verify-email?token={}",
+        mail_config.frontend_url, token
+    ))
+}
+
+/// Issues a fresh verification token for `user` and emails them the verification link
+pub(crate) fn send_verification_email(
+    conn: &mut PgConnection,
+    signer: &TokenSigner,
+    mailer: &dyn Mailer,
+    auth_config: &AuthConfig,
+    mail_config: &MailConfig,
+    user: &User,
+) -> Result<(), AppError> {
+    let link = verification_link(conn, signer, auth_config, mail_config, user)?;
+
+    mailer.send(&Email::verification(&user.email, &link))
+}
 
 #[post("/user/create")]
 async fn create_user(
     pool: web::Data<DbPool>,
-    form: web::Json<CreateUser>,
-) -> Result<impl Responder> {
-    // Creates a new user in the database.
+    signer: web::Data<TokenSigner>,
+    hasher: web::Data<PasswordHasher>,
+    mailer: web::Data<dyn Mailer>,
+    auth_config: web::Data<AuthConfig>,
+    mail_config: web::Data<MailConfig>,
+    form: ValidatedJson<CreateUser>,
+) -> Result<HttpResponse, AppError> {
+    // Creates a new user in the database and sends them an email verification link.
     //
     // # Parameters
     //
@@ -21,49 +89,217 @@ async fn create_user(
     //
     // # Returns
     //
-    // A `HttpResponse` with a JSON object containing the status, message, and user data if the user was created successfully.
-    // If the user could not be created, a `HttpResponse` with a JSON object containing an error message is returned.
+    // A `HttpResponse` with a JSON object containing the status and message if the user was created successfully.
     //
-    let mut conn = pool.get().unwrap();
-    let user: CreateUser = form.into_inner();
+    // # Errors
+    //
+    // An `AppError` is returned if the payload is invalid or a user with the same email already exists,
+    // unless `conceal_existing_accounts` is set, in which case the owner is emailed instead.
+    //
+    let mut user: CreateUser = form.into_inner();
+    user.email = normalize_email(&user.email, auth_config.lowercase_email_local_part);
 
-    if User::find_user_by_email(&mut conn, &user.email).is_ok() {
-        return Ok(HttpResponse::BadRequest().json(json!({
-            "status": "error",
-            "message": "User already exists",
-        })));
-    }
+    let email = user.email.clone();
+    let conceal_existing_accounts = auth_config.conceal_existing_accounts;
 
-    let created_user = User::add_user(
-        &mut conn,
-        CreateUser {
-            full_name: user.full_name.to_owned(),
-            email: user.email.to_owned(),
-            password: user.password.to_owned(),
-        },
-    );
-
-    match created_user {
-        Ok(_) => Ok(HttpResponse::Created().json(json!({
-            "status": "success",
-            "message": "User created",
-        }))),
-
-        Err(_) => Ok(HttpResponse::BadRequest().json(json!({
-            "status": "error",
-            "message": "Could not create user",
-        }))),
+    with_database_connection(&pool, move |conn| {
+        // Duplicate emails are rejected by the `users_email_lower_key` index with a 409
+        let created = conn.transaction(|conn| {
+            let created_user = User::add_user(conn, &hasher, user)?;
+            let link = verification_link(conn, &signer, &auth_config, &mail_config, &created_user)?;
+
+            Ok((created_user, link))
+        });
+        match created {
+            // The account exists at this point, so a lost email must not fail the signup;
+            // the user can ask for another one at `/user/verify-email/resend`
+            Ok((created_user, link)) => {
+                if let Err(err) = mailer.send(&Email::verification(&created_user.email, &link)) {
+                    eprintln!(
+                        "Failed to send the verification email of user {}: {}",
+                        created_user.id, err
+                    );
+                }
+
+                Ok(())
+            }
+            // The password was already hashed before the insert failed, so this path costs
+            // about as much as a real signup and the response is identical
+            Err(AppError::Conflict(_)) if conceal_existing_accounts => {
+                let link = format!("{}/forgot-password", mail_config.frontend_url);
+                mailer.send(&Email::account_exists(&email, &link))
+            }
+            Err(err) => Err(err),
+        }
+    })
+    .await?;
+
+    let message = if conceal_existing_accounts {
+        "Check your email to finish signing up"
+    } else {
+        "User created"
+    };
+    Ok(HttpResponse::Created().json(GenericResponse {
+        status: "success".to_string(),
+        message: message.to_string(),
+    }))
+}
+
+#[post("/user/verify-email")]
+async fn verify_email(
+    pool: web::Data<DbPool>,
+    signer: web::Data<TokenSigner>,
+    form: ValidatedJson<VerifyEmail>,
+) -> Result<HttpResponse, AppError> {
+    // Verifies the email address of the user the submitted token was issued to.
+    //
+    // # Errors
+    //
+    // An `AppError::InvalidToken` is returned if the token is unknown, was already used or has expired.
+    //
+    let form = form.into_inner();
+    with_database_connection(&pool, move |conn| {
+        EmailVerificationToken::consume(conn, &signer, &form.token)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Email verified".to_string(),
+    }))
+}
+
+#[post("/user/verify-email/resend")]
+async fn resend_verification_email(
+    pool: web::Data<DbPool>,
+    signer: web::Data<TokenSigner>,
+    mailer: web::Data<dyn Mailer>,
+    auth_config: web::Data<AuthConfig>,
+    mail_config: web::Data<MailConfig>,
+    form: ValidatedJson<ResendVerification>,
+) -> Result<HttpResponse, AppError> {
+    // Sends a new verification link, replacing any previously issued one.
+    //
+    // The response is the same whether or not the address belongs to an unverified account,
+    // so this endpoint cannot be used to discover registered emails.
+    //
+    let form = form.into_inner();
+    with_database_connection(&pool, move |conn| {
+        if let Ok(user) = User::find_user_by_email(conn, &form.email) {
+            if user.email_verified_at.is_none() {
+                send_verification_email(
+                    conn,
+                    &signer,
+                    mailer.get_ref(),
+                    &auth_config,
+                    &mail_config,
+                    &user,
+                )?;
+            }
+        }
+
+        Ok(())
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "If the account exists and is unverified, a verification email has been sent"
+            .to_string(),
+    }))
+}
+
+#[get("/user/me")]
+async fn get_profile(user: AuthenticatedUser) -> Result<HttpResponse, AppError> {
+    // Returns the profile of the currently authenticated user.
+    //
+    Ok(HttpResponse::Ok().json(ProfileResponse {
+        status: "success".to_string(),
+        message: "Profile".to_string(),
+        user: user.0.into(),
+    }))
+}
+
+#[patch("/user/me")]
+async fn update_profile(
+    pool: web::Data<DbPool>,
+    signer: web::Data<TokenSigner>,
+    mailer: web::Data<dyn Mailer>,
+    auth_config: web::Data<AuthConfig>,
+    mail_config: web::Data<MailConfig>,
+    user: AuthenticatedUser,
+    form: ValidatedJson<UpdateProfile>,
+) -> Result<HttpResponse, AppError> {
+    // Updates the name and/or email address of the currently authenticated user.
+    //
+    // A new email address is marked unverified and a verification link is sent to it.
+    //
+    // # Errors
+    //
+    // An `AppError::Conflict` is returned if the new email is already taken,
+    // an `AppError::Forbidden` if a personal API token tries to change the email.
+    //
+    let form = form.into_inner();
+
+    let new_email = form
+        .email
+        .map(|email| normalize_email(&email, auth_config.lowercase_email_local_part))
+        .filter(|email| *email != user.email);
+
+    let email_changed = new_email.is_some();
+    // The email is where password resets are sent, so changing it is an account security action
+    if email_changed {
+        user.reject_api_token()?;
     }
+    let updated_user = with_database_connection(&pool, move |conn| {
+        let updated_user = User::update_user(
+            conn,
+            user.id,
+            &UserChanges {
+                full_name: form.full_name,
+                email: new_email,
+                email_verified_at: email_changed.then_some(None),
+                ..Default::default()
+            },
+        )?;
+
+        if email_changed {
+            send_verification_email(
+                conn,
+                &signer,
+                mailer.get_ref(),
+                &auth_config,
+                &mail_config,
+                &updated_user,
+            )?;
+        }
+
+        Ok(updated_user)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(ProfileResponse {
+        status: "success".to_string(),
+        message: "Profile updated".to_string(),
+        user: updated_user.into(),
+    }))
 }
 
 #[delete("/user/me")]
-async fn delete_user(pool: web::Data<DbPool>, session: Session) -> Result<impl Responder> {
+async fn delete_user(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    session: Session,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
     // Deletes the currently authenticated user from the database.
     //
     // # Parameters
     //
     // * `pool`: The database connection pool.
+    // * `redis`: The Redis connection holding the session index.
     // * `session`: The user's session data.
+    // * `user`: The currently authenticated user.
     //
     // # Returns
     //
@@ -71,19 +307,296 @@ async fn delete_user(pool: web::Data<DbPool>, session: Session) -> Result<impl R
     //
     // # Errors
     //
-    // If the user could not be deleted, a `HttpResponse` with a JSON object containing an error message is returned.
+    // An `AppError` is returned if there is no valid session or the user could not be deleted.
+    //
+    user.reject_api_token()?;
+    let user_id = user.id;
+    with_database_connection(&pool, move |conn| User::delete_user(conn, user_id)).await?;
+    SessionIndex::revoke_all(&redis, user.id).await?;
+    session.purge();
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "User deleted".to_string(),
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[put("/user/me/password")]
+async fn change_password(
+    pool: web::Data<DbPool>,
+    redis: web::Data<RedisAddr>,
+    session_config: web::Data<SessionConfig>,
+    hasher: web::Data<PasswordHasher>,
+    signer: web::Data<TokenSigner>,
+    jwt_signer: web::Data<JwtSigner>,
+    jwt_config: web::Data<JwtConfig>,
+    throttle_config: web::Data<LoginThrottleConfig>,
+    session: Session,
+    req: HttpRequest,
+    user: AuthenticatedUser,
+    form: ValidatedJson<ChangePassword>,
+) -> Result<HttpResponse, AppError> {
+    // Changes the password of the currently authenticated user.
+    //
+    // Every existing session and JWT refresh token of the user is revoked, so credentials
+    // issued before the change stop working. A browser gets a fresh cookie session, a client
+    // using a JWT access token gets a new token pair in the same format as `/auth/token`.
+    //
+    // # Errors
+    //
+    // An `AppError::Unauthorized` is returned if there is no valid session or the current password is wrong.
     //
-    let mut conn = pool.get().unwrap();
-    let user_session = Auth::validate_session(&session).unwrap();
-    match User::delete_user(&mut conn, &user_session) {
-        Ok(_) => Ok(HttpResponse::Ok().json(json!({
-            "status": "success",
-            "message": "User deleted",
-        }))),
+    user.reject_api_token()?;
+    let form = form.into_inner();
+    let current_user = user.0.clone();
+
+    with_database_connection(&pool, move |conn| {
+        if !Auth::verify_password(&hasher, &current_user, &form.current_password)? {
+            return Err(AppError::Unauthorized(
+                "Current password is incorrect".to_string(),
+            ));
+        }
+        User::update_password(conn, &hasher, current_user.id, &form.new_password)?;
+
+        Ok(())
+    })
+    .await?;
+
+    SessionIndex::revoke_all(&redis, user.id).await?;
+    RefreshToken::revoke_all(&pool, &redis, &jwt_config, user.id).await?;
 
-        Err(_) => Ok(HttpResponse::Forbidden().json(json!({
-            "status": "error",
-            "message": "Could not delete user",
-        }))),
+    if let AuthMethod::AccessToken = user.1 {
+        let (family, refresh_token) = {
+            let (jwt_config, user_id) = (jwt_config.clone(), user.id);
+            with_database_connection(&pool, move |conn| {
+                RefreshToken::issue(conn, &signer, &jwt_config, user_id, None)
+            })
+            .await?
+        };
+        let mut tokens = token_response(&jwt_signer, &jwt_config, user.id, family, refresh_token)?;
+        tokens.message = "Password changed".to_string();
+
+        return Ok(HttpResponse::Ok().json(tokens));
+    }
+
+    Auth::start_session(
+        &session,
+        &redis,
+        &session_config,
+        &req,
+        throttle_config.trust_proxy_headers,
+        user.id,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Password changed".to_string(),
+    }))
+}
+
+#[get("/user/me/sessions")]
+async fn list_sessions(
+    redis: web::Data<RedisAddr>,
+    session_config: web::Data<SessionConfig>,
+    session: Session,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    // Lists the active sessions of the currently authenticated user,
+    // flagging the one this request was made with.
+    //
+    let current_id = Auth::current_session_id(&session);
+    let sessions = SessionIndex::list(&redis, &session_config, user.id)
+        .await?
+        .into_iter()
+        .map(|info| ActiveSession {
+            current: current_id.as_deref() == Some(info.id.as_str()),
+            info,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(SessionsResponse {
+        status: "success".to_string(),
+        message: "Active sessions".to_string(),
+        sessions,
+    }))
+}
+
+#[delete("/user/me/sessions/{id}")]
+async fn revoke_session(
+    redis: web::Data<RedisAddr>,
+    session: Session,
+    user: AuthenticatedUser,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    // Revokes one of the current user's sessions, logging that device out.
+    //
+    // # Errors
+    //
+    // An `AppError::NotFound` is returned if the user has no session with that id.
+    //
+    let session_id = path.into_inner();
+
+    if !SessionIndex::revoke(&redis, user.id, &session_id).await? {
+        return Err(AppError::NotFound("Session not found".to_string()));
+    }
+    if Auth::current_session_id(&session).as_deref() == Some(session_id.as_str()) {
+        session.purge();
+    }
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Session revoked".to_string(),
+    }))
+}
+
+#[get("/user/me/permissions")]
+async fn list_permissions(user: AuthenticatedUser) -> Result<HttpResponse, AppError> {
+    // Returns the role of the currently authenticated user and what it allows,
+    // so clients can hide actions the user is not allowed to perform.
+    //
+    Ok(HttpResponse::Ok().json(PermissionsResponse {
+        status: "success".to_string(),
+        message: "Permissions".to_string(),
+        role: user.role,
+        permissions: user.role.permissions().to_vec(),
+    }))
+}
+
+#[post("/user/me/2fa/setup")]
+async fn setup_two_factor(
+    pool: web::Data<DbPool>,
+    two_factor_config: web::Data<TwoFactorConfig>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, AppError> {
+    // Starts enrolling the current user in two-factor authentication.
+    //
+    // The returned secret only takes effect once a code generated from it is
+    // sent to `/user/me/2fa/confirm`; calling this again starts over with a new secret.
+    //
+    // # Errors
+    //
+    // An `AppError::Conflict` is returned if two-factor authentication is already enabled.
+    //
+    user.reject_api_token()?;
+    let (secret, otpauth_uri) = with_database_connection(&pool, move |conn| {
+        TwoFactor::begin_setup(conn, &two_factor_config, &user)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(TwoFactorSetupResponse {
+        status: "success".to_string(),
+        message: "Scan the URI with an authenticator app and confirm with a code".to_string(),
+        secret,
+        otpauth_uri,
+    }))
+}
+
+#[post("/user/me/2fa/confirm")]
+async fn confirm_two_factor(
+    pool: web::Data<DbPool>,
+    signer: web::Data<TokenSigner>,
+    two_factor_config: web::Data<TwoFactorConfig>,
+    user: AuthenticatedUser,
+    form: ValidatedJson<TwoFactorCode>,
+) -> Result<HttpResponse, AppError> {
+    // Enables two-factor authentication once the user proves their authenticator works.
+    //
+    // # Returns
+    //
+    // The recovery codes, which are not stored in plain text and cannot be shown again.
+    //
+    // # Errors
+    //
+    // An `AppError::InvalidToken` is returned if the code is wrong.
+    //
+    user.reject_api_token()?;
+    let form = form.into_inner();
+    let recovery_codes = with_database_connection(&pool, move |conn| {
+        TwoFactor::confirm(conn, &signer, &two_factor_config, &user, &form.code)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodesResponse {
+        status: "success".to_string(),
+        message: "Two-factor authentication enabled".to_string(),
+        recovery_codes,
+    }))
+}
+
+#[post("/user/me/2fa/disable")]
+async fn disable_two_factor(
+    pool: web::Data<DbPool>,
+    signer: web::Data<TokenSigner>,
+    hasher: web::Data<PasswordHasher>,
+    two_factor_config: web::Data<TwoFactorConfig>,
+    user: AuthenticatedUser,
+    form: ValidatedJson<DisableTwoFactor>,
+) -> Result<HttpResponse, AppError> {
+    // Disables two-factor authentication, which needs both the password and a current code
+    // so a hijacked session alone cannot strip the second factor.
+    //
+    // # Errors
+    //
+    // An `AppError::Unauthorized` is returned if the password or the code is wrong.
+    //
+    user.reject_api_token()?;
+
+    if !user.two_factor_enabled() {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication is not enabled".to_string(),
+        ));
     }
+    let form = form.into_inner();
+    with_database_connection(&pool, move |conn| {
+        if !Auth::verify_password(&hasher, &user, &form.password)? {
+            return Err(AppError::Unauthorized("Password is incorrect".to_string()));
+        }
+        if !TwoFactor::verify(conn, &signer, &two_factor_config, &user, &form.code)? {
+            return Err(AppError::Unauthorized(
+                "Invalid two-factor authentication code".to_string(),
+            ));
+        }
+        TwoFactor::disable(conn, user.id)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(GenericResponse {
+        status: "success".to_string(),
+        message: "Two-factor authentication disabled".to_string(),
+    }))
+}
+
+#[post("/user/me/2fa/recovery-codes")]
+async fn regenerate_recovery_codes(
+    pool: web::Data<DbPool>,
+    signer: web::Data<TokenSigner>,
+    two_factor_config: web::Data<TwoFactorConfig>,
+    user: AuthenticatedUser,
+    form: ValidatedJson<TwoFactorCode>,
+) -> Result<HttpResponse, AppError> {
+    // Replaces the recovery codes of the current user, invalidating the old ones.
+    //
+    // # Errors
+    //
+    // An `AppError::Unauthorized` is returned if the code is wrong.
+    //
+    user.reject_api_token()?;
+    let form = form.into_inner();
+    let recovery_codes = with_database_connection(&pool, move |conn| {
+        if !TwoFactor::verify(conn, &signer, &two_factor_config, &user, &form.code)? {
+            return Err(AppError::Unauthorized(
+                "Invalid two-factor authentication code".to_string(),
+            ));
+        }
+        TwoFactor::issue_recovery_codes(conn, &signer, &two_factor_config, user.id)
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodesResponse {
+        status: "success".to_string(),
+        message: "Recovery codes regenerated".to_string(),
+        recovery_codes,
+    }))
 }
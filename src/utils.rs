@@ -1,2 +1,11 @@
 pub mod config;
+pub mod email;
 pub mod helpers;
+pub mod jwt;
+pub mod oidc;
+pub mod password;
+pub mod rate_limit_store;
+pub mod redis;
+pub mod settings;
+pub mod tls;
+pub mod tokens;
@@ -1,31 +1,470 @@
-use actix_web::error::ErrorInternalServerError;
+use actix_web::cookie::SameSite;
+use actix_web::web;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
 use diesel::{r2d2, PgConnection};
-use std::fs::File;
-use std::io::Read;
-use toml::Value;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::errors::AppError;
+
 type DbPool = r2d2::Pool<r2d2::ConnectionManager<PgConnection>>;
 
-pub fn load_config(filename: &str) -> Result<Value, Box<dyn std::error::Error>> {
-    let mut file = File::open(filename)?;
-    let mut toml_str = String::new();
-    file.read_to_string(&mut toml_str)?;
-    let toml_value: Value = toml::de::from_str(&toml_str)?;
-    Ok(toml_value)
+/// Prefix of the `server.listen` entries naming a Unix domain socket rather than `host:port`
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// `[server]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `host:port` addresses and `unix:/path` sockets to listen on
+    pub listen: Vec<String>,
+    /// Worker threads, one per physical CPU core when unset
+    pub workers: Option<usize>,
+    /// How long idle keep-alive connections stay open, 0 disables keep-alive
+    pub keep_alive_seconds: u64,
+    /// Largest JSON body accepted
+    pub max_json_bytes: usize,
+    /// Largest body accepted by the other extractors
+    pub max_payload_bytes: usize,
+    /// Serve HTTPS instead of HTTP on the `host:port` addresses
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        // The same limits as actix-web's own defaults
+        ServerConfig {
+            listen: vec!["127.0.0.1:8080".to_string()],
+            workers: None,
+            keep_alive_seconds: 5,
+            max_json_bytes: 2 * 1024 * 1024,
+            max_payload_bytes: 256 * 1024,
+            tls: None,
+        }
+    }
+}
+
+/// `[server.tls]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf certificate first
+    pub cert_path: String,
+    /// PEM file with the PKCS#8, PKCS#1 or SEC1 private key
+    pub key_path: String,
+}
+
+/// `[db]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// `postgres://` connection URL, required
+    pub url: String,
+    pub max_connections: u32,
+    /// Apply pending migrations when the server starts instead of refusing to start
+    pub run_migrations: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: String::new(),
+            max_connections: 10,
+            run_migrations: false,
+        }
+    }
+}
+
+/// `[redis]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    /// `host:port` of the server holding sessions, lockouts and short-lived tokens
+    pub address: String,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig {
+            address: "127.0.0.1:6379".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSameSite {
+    Strict,
+    Lax,
+    /// Sent on cross-site requests too, browsers only accept it on secure cookies
+    None,
+}
+
+impl From<CookieSameSite> for SameSite {
+    fn from(same_site: CookieSameSite) -> Self {
+        match same_site {
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::None => SameSite::None,
+        }
+    }
 }
 
-pub fn establish_connection(database_url: &str) -> DbPool {
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
+/// `[session]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// How long a session stays alive without being used
+    pub ttl_days: i64,
+    pub cookie_name: String,
+    /// Only send the cookie over HTTPS, turn off for local development over plain HTTP
+    pub cookie_secure: bool,
+    pub cookie_same_site: CookieSameSite,
+    /// Domain the cookie is scoped to, the exact host of the request when unset
+    pub cookie_domain: Option<String>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            ttl_days: 14,
+            cookie_name: "id".to_string(),
+            cookie_secure: true,
+            cookie_same_site: CookieSameSite::Lax,
+            cookie_domain: None,
+        }
+    }
+}
+
+/// `[security]` section of `settings.toml`
+///
+/// Both secrets are required. Prefer setting them through `APP_SECURITY__KEY_SECRET` and
+/// `APP_SECURITY__JWT_SECRET` over writing them to the file.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// Signs session cookies and derives the hashes of stored tokens
+    pub key_secret: String,
+    /// Signs the JWT access tokens of `POST /auth/token`
+    pub jwt_secret: String,
+}
+
+/// `[auth]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Refuse to log in accounts that have not verified their email address
+    pub require_verified_email: bool,
+    /// Lowercase the part of email addresses before the `@` as well as the domain
+    pub lowercase_email_local_part: bool,
+    /// Answer signups for registered emails like new ones and email the owner instead,
+    /// so the signup form cannot be used to find out who has an account
+    pub conceal_existing_accounts: bool,
+    pub verification_token_ttl_minutes: i64,
+    pub password_reset_token_ttl_minutes: i64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            require_verified_email: false,
+            lowercase_email_local_part: true,
+            conceal_existing_accounts: false,
+            verification_token_ttl_minutes: 24 * 60,
+            password_reset_token_ttl_minutes: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordAlgorithm {
+    Bcrypt,
+    Argon2id,
+}
+
+/// `[password]` section of `settings.toml`
+///
+/// New passwords are hashed with `algorithm`. Hashes made with another algorithm or other
+/// parameters keep working and are replaced the next time their owner logs in.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordConfig {
+    pub algorithm: PasswordAlgorithm,
+    pub bcrypt_cost: u32,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        // Argon2id parameters recommended by OWASP
+        PasswordConfig {
+            algorithm: PasswordAlgorithm::Argon2id,
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+        }
+    }
+}
+
+/// `[login_throttle]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginThrottleConfig {
+    pub enabled: bool,
+    /// Failed attempts allowed per account within `window_seconds` before it is locked
+    pub max_attempts_per_account: i64,
+    /// Failed attempts allowed per client IP within `window_seconds` before it is locked
+    pub max_attempts_per_ip: i64,
+    pub window_seconds: i64,
+    /// Length of the first lockout, doubled for every further lockout within a day
+    pub lockout_seconds: i64,
+    pub max_lockout_seconds: i64,
+    /// Take the client IP from `Forwarded`/`X-Forwarded-For`, only safe behind a trusted proxy;
+    /// also applies to the IP recorded in the session index
+    pub trust_proxy_headers: bool,
+}
+
+impl Default for LoginThrottleConfig {
+    fn default() -> Self {
+        LoginThrottleConfig {
+            enabled: true,
+            max_attempts_per_account: 5,
+            max_attempts_per_ip: 20,
+            window_seconds: 15 * 60,
+            lockout_seconds: 60,
+            max_lockout_seconds: 60 * 60,
+            trust_proxy_headers: false,
+        }
+    }
+}
+
+/// `[jwt]` section of `settings.toml`, the signing key is `security.jwt_secret`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    /// Lifetime of access tokens, which cannot be revoked individually before they expire
+    pub access_token_ttl_seconds: i64,
+    pub refresh_token_ttl_days: i64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            access_token_ttl_seconds: 15 * 60,
+            refresh_token_ttl_days: 30,
+        }
+    }
+}
+
+/// `[two_factor]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwoFactorConfig {
+    /// Name authenticator apps show next to the account
+    pub issuer: String,
+    /// How long the pending login token returned after the password step stays valid
+    pub login_token_ttl_seconds: i64,
+    /// Recovery codes issued when 2FA is enabled or the codes are regenerated
+    pub recovery_code_count: usize,
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        TwoFactorConfig {
+            issuer: "Accounts".to_string(),
+            login_token_ttl_seconds: 5 * 60,
+            recovery_code_count: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+    /// Per-process buckets, for development and tests
+    Memory,
+    /// Buckets shared by every instance through Redis
+    Redis,
+}
+
+/// What requests are grouped by when counting them against a rule
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    Ip,
+    /// The logged in user, falling back to the client IP for anonymous requests
+    User,
+    /// The personal API token sent as a bearer token or `X-Api-Key` header, falling back
+    /// to the client IP when there is none or it is not a valid token
+    ApiKey,
+}
+
+/// A token bucket applied to every request whose path matches `pattern`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRule {
+    /// Route pattern, `{name}` matches a single path segment and a trailing `*` anything
+    pub pattern: String,
+    /// HTTP methods the rule applies to, all methods when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub key: RateLimitKey,
+    /// Bucket size, i.e. the largest burst allowed
+    pub limit: u32,
+    /// Time it takes for an empty bucket to refill completely
+    pub period_seconds: u64,
+}
+
+/// `[rate_limit]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub backend: RateLimitBackend,
+    /// Take the client IP from `Forwarded`/`X-Forwarded-For`, only safe behind a trusted proxy
+    pub trust_proxy_headers: bool,
+    /// Checked in order, the first rule matching a request applies
+    pub rules: Vec<RateLimitRule>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            backend: RateLimitBackend::Redis,
+            trust_proxy_headers: false,
+            rules: vec![RateLimitRule {
+                pattern: "/user/create".to_string(),
+                methods: vec!["POST".to_string()],
+                key: RateLimitKey::Ip,
+                limit: 5,
+                period_seconds: 60 * 60,
+            }],
+        }
+    }
+}
+
+/// An OpenID Connect provider users can sign in with
+///
+/// Endpoints are configured explicitly rather than discovered, so tests can point them
+/// at a local mock server.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OidcProviderConfig {
+    /// The `issuer` of the provider's discovery document, the `iss` its ID tokens must carry
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    /// Our callback, `{public url}/auth/oidc/{provider}/callback`, registered with the provider
+    pub redirect_uri: String,
+    #[serde(default = "OidcProviderConfig::default_scopes")]
+    pub scopes: Vec<String>,
+}
+
+impl OidcProviderConfig {
+    fn default_scopes() -> Vec<String> {
+        vec![
+            "openid".to_string(),
+            "email".to_string(),
+            "profile".to_string(),
+        ]
+    }
+}
+
+/// `[oidc]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Time allowed between starting a sign in and the provider redirecting back
+    pub state_ttl_seconds: i64,
+    /// Providers by the name used in URLs, e.g. `[oidc.providers.google]`
+    pub providers: HashMap<String, OidcProviderConfig>,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            state_ttl_seconds: 10 * 60,
+            providers: HashMap::new(),
+        }
+    }
+}
+
+impl OidcConfig {
+    /// Settings of the provider called `name`, `AppError::NotFound` if it is not configured
+    pub fn provider(&self, name: &str) -> Result<&OidcProviderConfig, AppError> {
+        self.providers
+            .get(name)
+            .ok_or_else(|| AppError::NotFound("Unknown identity provider".to_string()))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailBackend {
+    Log,
+    File,
+}
+
+/// `[mail]` section of `settings.toml`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub backend: MailBackend,
+    pub from: String,
+    /// Directory the `file` backend writes messages to
+    pub outbox_dir: String,
+    /// Base URL of the frontend, used to build links sent by email
+    pub frontend_url: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            backend: MailBackend::Log,
+            from: "no-reply@localhost".to_string(),
+            outbox_dir: "outbox".to_string(),
+            frontend_url: "http://localhost:3000".to_string(),
+        }
+    }
+}
+
+pub fn establish_connection(config: &DatabaseConfig) -> DbPool {
+    let manager = ConnectionManager::<PgConnection>::new(&config.url);
     Pool::builder()
+        .max_size(config.max_connections)
         .build(manager)
         .expect("Failed to connect to database")
 }
 
 pub fn get_database_connection(
     pool: &DbPool,
-) -> Result<PooledConnection<ConnectionManager<PgConnection>>, actix_web::Error> {
-    pool.get().map_err(|err| {
-        eprintln!("Failed to get database connection: {}", err);
-        ErrorInternalServerError("Internal Server Error")
+) -> Result<PooledConnection<ConnectionManager<PgConnection>>, AppError> {
+    Ok(pool.get()?)
+}
+
+/// Run `query` with a pooled connection on the blocking thread pool
+///
+/// Waiting for a free connection, diesel queries and the password hashing some actors do
+/// all block the calling thread, which on an actix worker would stall every other request
+/// it serves. Handlers go through this rather than `get_database_connection`, which is left
+/// to the command-line interface and startup.
+pub async fn with_database_connection<F, T>(pool: &DbPool, query: F) -> Result<T, AppError>
+where
+    F: FnOnce(&mut PgConnection) -> Result<T, AppError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let mut conn = get_database_connection(&pool)?;
+        query(&mut conn)
     })
+    .await?
 }
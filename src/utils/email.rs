@@ -0,0 +1,56 @@
+use serde::{Deserialize, Deserializer};
+
+/// Canonical form of an email address, used for storage
+///
+/// Surrounding whitespace is removed and the domain is lowercased, since domains are
+/// case insensitive. The local part is technically case sensitive, but virtually every
+/// provider treats it as insensitive, so it is lowercased as well unless
+/// `auth.lowercase_email_local_part` is turned off.
+pub fn normalize_email(email: &str, lowercase_local_part: bool) -> String {
+    let email = email.trim();
+    match email.rsplit_once('@') {
+        Some((local, domain)) if lowercase_local_part => {
+            format!("{}@{}", local.to_lowercase(), domain.to_lowercase())
+        }
+        Some((local, domain)) => format!("{}@{}", local, domain.to_lowercase()),
+        None => email.to_string(),
+    }
+}
+
+/// `deserialize_with` helper trimming email fields before they are validated
+pub fn trimmed<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(String::deserialize(deserializer)?.trim().to_string())
+}
+
+/// `deserialize_with` helper trimming optional email fields before they are validated
+pub fn trimmed_option<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.map(|email| email.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_email_trims_and_lowercases() {
+        assert_eq!(
+            normalize_email("  Bob@Example.COM ", true),
+            "bob@example.com"
+        );
+        assert_eq!(
+            normalize_email("  Bob@Example.COM ", false),
+            "Bob@example.com"
+        );
+    }
+
+    #[test]
+    fn normalize_email_splits_on_the_last_at_sign() {
+        assert_eq!(
+            normalize_email("\"A@B\"@Example.com", false),
+            "\"A@B\"@example.com"
+        );
+        assert_eq!(normalize_email(" Not An Email ", true), "Not An Email");
+    }
+}
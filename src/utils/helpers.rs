@@ -1,4 +1,4 @@
-use actix_web::cookie::Key;
+use actix_web::{cookie::Key, HttpRequest};
 use diesel::{r2d2, PgConnection};
 
 pub type DbPool = r2d2::Pool<r2d2::ConnectionManager<PgConnection>>;
@@ -6,3 +6,18 @@ pub type DbPool = r2d2::Pool<r2d2::ConnectionManager<PgConnection>>;
 pub fn get_secret_key(value: &str) -> Key {
     Key::derive_from(value.as_bytes())
 }
+
+/// IP address of the client that sent `req`
+///
+/// Forwarding headers are only honoured when `trust_proxy_headers` is set, otherwise any
+/// client could pick its own address and dodge per-IP limits.
+pub fn client_ip(req: &HttpRequest, trust_proxy_headers: bool) -> String {
+    let connection_info = req.connection_info();
+    let ip = if trust_proxy_headers {
+        connection_info.realip_remote_addr()
+    } else {
+        connection_info.peer_addr()
+    };
+
+    ip.unwrap_or("unknown").to_string()
+}
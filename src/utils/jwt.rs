@@ -0,0 +1,63 @@
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::errors::AppError;
+
+/// Claims of the access tokens issued by `POST /auth/token`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessClaims {
+    /// Id of the user
+    pub sub: Uuid,
+    /// Refresh token family the access token was issued from, checked against revoked families
+    pub fam: Uuid,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+/// Signs and verifies HS256 JWT access tokens with `security.jwt_secret`
+#[derive(Clone)]
+pub struct JwtSigner {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtSigner {
+    pub fn new(secret: &str) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+
+        JwtSigner {
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+        }
+    }
+
+    /// Issue an access token for `user_id`, valid for `ttl`
+    pub fn issue(&self, user_id: Uuid, family_id: Uuid, ttl: Duration) -> Result<String, AppError> {
+        let now = Utc::now();
+        let claims = AccessClaims {
+            sub: user_id,
+            fam: family_id,
+            iat: now.timestamp(),
+            exp: (now + ttl).timestamp(),
+        };
+
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding_key)
+            .map_err(|err| AppError::Internal(format!("Failed to sign access token: {}", err)))
+    }
+
+    /// Check the signature and expiry of an access token
+    ///
+    /// # Returns
+    ///
+    /// The claims of the token, or `AppError::Unauthorized` if it is malformed, forged or expired
+    pub fn verify(&self, token: &str) -> Result<AccessClaims, AppError> {
+        decode::<AccessClaims>(token, &self.decoding_key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|_| AppError::Unauthorized("Invalid or expired access token".to_string()))
+    }
+}
@@ -0,0 +1,221 @@
+use std::sync::Arc;
+
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString};
+use argon2::{Argon2, Params, Version};
+use bcrypt::HashParts;
+use rand::rngs::OsRng;
+
+use crate::errors::AppError;
+use crate::utils::config::{PasswordAlgorithm, PasswordConfig};
+
+/// A password hashing algorithm producing self-describing `$`-prefixed hash strings
+trait HashScheme: Send + Sync {
+    /// Whether `hash` was produced by this algorithm
+    fn recognizes(&self, hash: &str) -> bool;
+    fn hash(&self, password: &str) -> Result<String, AppError>;
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, AppError>;
+    /// Whether `hash` was produced with other parameters than the configured ones
+    fn is_outdated(&self, hash: &str) -> bool;
+}
+
+struct BcryptScheme {
+    cost: u32,
+}
+
+impl HashScheme for BcryptScheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$2")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, AppError> {
+        Ok(bcrypt::hash(password.as_bytes(), self.cost)?)
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, AppError> {
+        Ok(bcrypt::verify(password, hash)?)
+    }
+
+    fn is_outdated(&self, hash: &str) -> bool {
+        hash.parse::<HashParts>()
+            .map_or(true, |parts| parts.get_cost() != self.cost)
+    }
+}
+
+/// Argon2id, stored as a PHC string such as `$argon2id$v=19$m=19456,t=2,p=1$...`
+struct Argon2Scheme {
+    argon2: Argon2<'static>,
+}
+
+impl HashScheme for Argon2Scheme {
+    fn recognizes(&self, hash: &str) -> bool {
+        hash.starts_with("$argon2")
+    }
+
+    fn hash(&self, password: &str) -> Result<String, AppError> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.argon2
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(AppError::from)
+    }
+
+    fn verify(&self, password: &str, hash: &str) -> Result<bool, AppError> {
+        let hash = PasswordHash::new(hash)?;
+
+        // The parameters of the stored hash are used, not the configured ones
+        match self.argon2.verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn is_outdated(&self, hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return true;
+        };
+        let Ok(params) = Params::try_from(&hash) else {
+            return true;
+        };
+        let current = self.argon2.params();
+
+        hash.algorithm != argon2::Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+            || params.m_cost() != current.m_cost()
+            || params.t_cost() != current.t_cost()
+            || params.p_cost() != current.p_cost()
+    }
+}
+
+/// Hashes and verifies passwords with the algorithms of the `[password]` section
+///
+/// Verification picks the algorithm from the stored hash itself, so bcrypt and argon2id
+/// hashes can live side by side while the user base migrates.
+#[derive(Clone)]
+pub struct PasswordHasher {
+    preferred: Arc<dyn HashScheme>,
+    schemes: Arc<[Arc<dyn HashScheme>]>,
+    /// Hash of a random password, checked against when there is no real hash to check
+    dummy_hash: Arc<str>,
+}
+
+impl PasswordHasher {
+    pub fn new(config: &PasswordConfig) -> Result<Self, AppError> {
+        let params = Params::new(
+            config.argon2_memory_kib,
+            config.argon2_iterations,
+            config.argon2_parallelism,
+            None,
+        )
+        .map_err(|err| AppError::Internal(format!("Invalid argon2 parameters: {}", err)))?;
+
+        let bcrypt: Arc<dyn HashScheme> = Arc::new(BcryptScheme {
+            cost: config.bcrypt_cost,
+        });
+        let argon2: Arc<dyn HashScheme> = Arc::new(Argon2Scheme {
+            argon2: Argon2::new(argon2::Algorithm::Argon2id, Version::V0x13, params),
+        });
+        let preferred = match config.algorithm {
+            PasswordAlgorithm::Bcrypt => Arc::clone(&bcrypt),
+            PasswordAlgorithm::Argon2id => Arc::clone(&argon2),
+        };
+        let dummy_hash = preferred.hash(&uuid::Uuid::new_v4().to_string())?.into();
+
+        Ok(PasswordHasher {
+            preferred,
+            schemes: Arc::new([bcrypt, argon2]),
+            dummy_hash,
+        })
+    }
+
+    fn scheme_for(&self, hash: &str) -> Option<&dyn HashScheme> {
+        self.schemes
+            .iter()
+            .find(|scheme| scheme.recognizes(hash))
+            .map(|scheme| scheme.as_ref())
+    }
+
+    /// Hash `password` with the preferred algorithm
+    pub fn hash(&self, password: &str) -> Result<String, AppError> {
+        self.preferred.hash(password)
+    }
+
+    /// Check `password` against a stored hash of any supported algorithm
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool, AppError> {
+        match self.scheme_for(hash) {
+            Some(scheme) => scheme.verify(password, hash),
+            None => Err(AppError::Internal(
+                "Password hash of an unknown algorithm".to_string(),
+            )),
+        }
+    }
+
+    /// Run a check that always fails, costing as much as verifying a current hash
+    pub fn verify_dummy(&self, password: &str) -> Result<(), AppError> {
+        self.preferred.verify(password, &self.dummy_hash)?;
+
+        Ok(())
+    }
+
+    /// Whether `hash` should be replaced by a hash with the preferred algorithm and parameters
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        !self.preferred.recognizes(hash) || self.preferred.is_outdated(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hasher(
+        algorithm: PasswordAlgorithm,
+        bcrypt_cost: u32,
+        argon2_iterations: u32,
+    ) -> PasswordHasher {
+        PasswordHasher::new(&PasswordConfig {
+            algorithm,
+            bcrypt_cost,
+            argon2_memory_kib: 64,
+            argon2_iterations,
+            argon2_parallelism: 1,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn verifies_hashes_of_either_algorithm() {
+        let bcrypt = hasher(PasswordAlgorithm::Bcrypt, 4, 1);
+        let argon2 = hasher(PasswordAlgorithm::Argon2id, 4, 1);
+        let bcrypt_hash = bcrypt.hash("hunter22").unwrap();
+        let argon2_hash = argon2.hash("hunter22").unwrap();
+        assert!(bcrypt_hash.starts_with("$2"));
+        assert!(argon2_hash.starts_with("$argon2id$"));
+
+        for hasher in [&bcrypt, &argon2] {
+            assert!(hasher.verify("hunter22", &bcrypt_hash).unwrap());
+            assert!(!hasher.verify("hunter23", &bcrypt_hash).unwrap());
+            assert!(hasher.verify("hunter22", &argon2_hash).unwrap());
+            assert!(!hasher.verify("hunter23", &argon2_hash).unwrap());
+            assert!(hasher.verify("hunter22", "plain text").is_err());
+        }
+    }
+
+    #[test]
+    fn rehashes_other_algorithms_and_parameters() {
+        let bcrypt = hasher(PasswordAlgorithm::Bcrypt, 4, 1);
+        let argon2 = hasher(PasswordAlgorithm::Argon2id, 4, 1);
+        let bcrypt_hash = bcrypt.hash("hunter22").unwrap();
+        let argon2_hash = argon2.hash("hunter22").unwrap();
+
+        assert!(!bcrypt.needs_rehash(&bcrypt_hash));
+        assert!(bcrypt.needs_rehash(&argon2_hash));
+        assert!(!argon2.needs_rehash(&argon2_hash));
+        assert!(argon2.needs_rehash(&bcrypt_hash));
+
+        assert!(hasher(PasswordAlgorithm::Bcrypt, 5, 1).needs_rehash(&bcrypt_hash));
+        assert!(hasher(PasswordAlgorithm::Argon2id, 4, 2).needs_rehash(&argon2_hash));
+        let argon2i_hash = argon2_hash.replacen("$argon2id$", "$argon2i$", 1);
+        assert!(argon2.needs_rehash(&argon2i_hash));
+        assert!(argon2.needs_rehash("plain text"));
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_redis::{resp_array, RespValue};
+use chrono::Utc;
+use futures_util::future::LocalBoxFuture;
+
+use crate::errors::AppError;
+use crate::utils::config::{RateLimitBackend, RateLimitConfig};
+use crate::utils::redis::{bulk_string, execute, integer, RedisAddr};
+
+/// Outcome of taking a token from a bucket
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again
+    pub reset_seconds: u64,
+    /// Seconds until the next token is available, 0 if one is available now
+    pub retry_after_seconds: u64,
+}
+
+impl RateLimitDecision {
+    fn new(allowed: bool, tokens: f64, limit: u32, period_seconds: u64) -> Self {
+        let tokens_per_second = limit as f64 / period_seconds.max(1) as f64;
+        RateLimitDecision {
+            allowed,
+            limit,
+            remaining: tokens.floor().max(0.0) as u32,
+            reset_seconds: ((limit as f64 - tokens) / tokens_per_second)
+                .ceil()
+                .max(0.0) as u64,
+            retry_after_seconds: ((1.0 - tokens) / tokens_per_second).ceil().max(0.0) as u64,
+        }
+    }
+}
+
+/// Storage for token buckets, shared by every worker of the server
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket `key`, holding at most `limit` tokens and refilling
+    /// completely over `period_seconds`
+    fn acquire(
+        &self,
+        key: String,
+        limit: u32,
+        period_seconds: u64,
+    ) -> LocalBoxFuture<'static, Result<RateLimitDecision, AppError>>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// Size and refill rate of the rule the bucket was created for, so pruning judges
+    /// every bucket by its own rule
+    capacity: f64,
+    tokens_per_second: f64,
+}
+
+impl Bucket {
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        (self.tokens + elapsed * self.tokens_per_second).min(self.capacity)
+    }
+}
+
+#[derive(Default)]
+struct MemoryBuckets {
+    buckets: HashMap<String, Bucket>,
+    pruned_at: Option<Instant>,
+}
+
+/// Buckets kept in process memory, shared by every worker of the server
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    state: Mutex<MemoryBuckets>,
+}
+
+/// Number of buckets after which full ones are dropped, bounding memory use
+const MEMORY_STORE_PRUNE_THRESHOLD: usize = 10_000;
+
+/// Shortest time between two prunes, so a flood of keys does not make every request scan
+/// all the buckets
+const MEMORY_STORE_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+impl MemoryRateLimitStore {
+    fn acquire_at(
+        &self,
+        key: String,
+        limit: u32,
+        period_seconds: u64,
+        now: Instant,
+    ) -> RateLimitDecision {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let prune_due = state
+            .pruned_at
+            .is_none_or(|pruned_at| now.duration_since(pruned_at) >= MEMORY_STORE_PRUNE_INTERVAL);
+        if state.buckets.len() > MEMORY_STORE_PRUNE_THRESHOLD && prune_due {
+            // A full bucket holds nothing a new one would not
+            state
+                .buckets
+                .retain(|_, bucket| bucket.tokens_at(now) < bucket.capacity);
+            state.pruned_at = Some(now);
+        }
+
+        let capacity = limit as f64;
+        let bucket = state.buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+            capacity,
+            tokens_per_second: capacity / period_seconds.max(1) as f64,
+        });
+        let mut tokens = bucket.tokens_at(now);
+        let allowed = tokens >= 1.0;
+        if allowed {
+            tokens -= 1.0;
+        }
+        bucket.tokens = tokens;
+        bucket.updated_at = now;
+
+        RateLimitDecision::new(allowed, tokens, limit, period_seconds)
+    }
+}
+
+impl RateLimitStore for MemoryRateLimitStore {
+    fn acquire(
+        &self,
+        key: String,
+        limit: u32,
+        period_seconds: u64,
+    ) -> LocalBoxFuture<'static, Result<RateLimitDecision, AppError>> {
+        let decision = self.acquire_at(key, limit, period_seconds, Instant::now());
+        Box::pin(async move { Ok(decision) })
+    }
+}
+
+/// Refills and takes from a bucket atomically, returning `{allowed, tokens}`
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local tokens_per_ms = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at')
+local tokens = tonumber(bucket[1]) or capacity
+local updated_at = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - updated_at) * tokens_per_ms)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated_at', tostring(now))
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+return {allowed, tostring(tokens)}
+"#;
+
+/// Buckets stored in Redis, shared by every instance of the server
+pub struct RedisRateLimitStore {
+    redis: RedisAddr,
+}
+
+impl RateLimitStore for RedisRateLimitStore {
+    fn acquire(
+        &self,
+        key: String,
+        limit: u32,
+        period_seconds: u64,
+    ) -> LocalBoxFuture<'static, Result<RateLimitDecision, AppError>> {
+        let redis = self.redis.clone();
+        let tokens_per_ms = limit as f64 / (period_seconds.max(1) * 1000) as f64;
+
+        Box::pin(async move {
+            let reply = execute(
+                &redis,
+                resp_array![
+                    "EVAL",
+                    TOKEN_BUCKET_SCRIPT,
+                    "1",
+                    format!("rate_limit:{}", key),
+                    limit.to_string(),
+                    tokens_per_ms.to_string(),
+                    Utc::now().timestamp_millis().to_string(),
+                    period_seconds.max(1).to_string()
+                ],
+            )
+            .await?;
+
+            let (allowed, tokens) = match reply {
+                RespValue::Array(mut values) if values.len() == 2 => {
+                    let tokens =
+                        bulk_string(values.remove(1)).and_then(|tokens| tokens.parse::<f64>().ok());
+                    (integer(&values[0]), tokens)
+                }
+                _ => (None, None),
+            };
+            match (allowed, tokens) {
+                (Some(allowed), Some(tokens)) => Ok(RateLimitDecision::new(
+                    allowed == 1,
+                    tokens,
+                    limit,
+                    period_seconds,
+                )),
+                _ => Err(AppError::Redis(
+                    "Unexpected reply from rate limit script".to_string(),
+                )),
+            }
+        })
+    }
+}
+
+pub fn build_rate_limit_store(
+    config: &RateLimitConfig,
+    redis: &RedisAddr,
+) -> Arc<dyn RateLimitStore> {
+    match config.backend {
+        RateLimitBackend::Memory => Arc::new(MemoryRateLimitStore::default()),
+        RateLimitBackend::Redis => Arc::new(RedisRateLimitStore {
+            redis: redis.clone(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store_denies_once_the_bucket_is_empty() {
+        let store = MemoryRateLimitStore::default();
+        let now = Instant::now();
+
+        for remaining in [1, 0] {
+            let decision = store.acquire_at("ip:a".to_string(), 2, 60, now);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let decision = store.acquire_at("ip:a".to_string(), 2, 60, now);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after_seconds, 30);
+        assert_eq!(decision.reset_seconds, 60);
+
+        // Other keys have their own bucket
+        assert!(store.acquire_at("ip:b".to_string(), 2, 60, now).allowed);
+    }
+
+    #[test]
+    fn memory_store_refills_over_the_period() {
+        let store = MemoryRateLimitStore::default();
+        let now = Instant::now();
+        for _ in 0..2 {
+            store.acquire_at("ip:a".to_string(), 2, 60, now);
+        }
+
+        let later = now + Duration::from_secs(30);
+        let decision = store.acquire_at("ip:a".to_string(), 2, 60, later);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(!store.acquire_at("ip:a".to_string(), 2, 60, later).allowed);
+
+        // Never more than `limit` tokens, however long the bucket sat unused
+        let much_later = later + Duration::from_secs(3600);
+        let decision = store.acquire_at("ip:a".to_string(), 2, 60, much_later);
+        assert_eq!(decision.remaining, 1);
+    }
+
+    #[test]
+    fn memory_store_prunes_full_buckets_by_their_own_rule() {
+        let store = MemoryRateLimitStore::default();
+        let now = Instant::now();
+        // A slow rule whose bucket is still refilling an hour later
+        store.acquire_at("0:ip:slow".to_string(), 10, 86_400, now);
+        for index in 0..MEMORY_STORE_PRUNE_THRESHOLD {
+            store.acquire_at(format!("1:ip:{}", index), 100, 1, now);
+        }
+
+        let later = now + Duration::from_secs(3600);
+        store.acquire_at("1:ip:trigger".to_string(), 100, 1, later);
+        let state = store.state.lock().unwrap();
+        assert!(state.buckets.contains_key("0:ip:slow"));
+        assert_eq!(state.buckets.len(), 2);
+        assert_eq!(state.pruned_at, Some(later));
+    }
+}
@@ -0,0 +1,33 @@
+use actix::Addr;
+use actix_redis::{Command, RedisActor, RespValue};
+
+use crate::errors::AppError;
+
+pub type RedisAddr = Addr<RedisActor>;
+
+/// Sends a command to Redis, turning transport failures and error replies into `AppError::Redis`
+pub async fn execute(redis: &RedisAddr, command: RespValue) -> Result<RespValue, AppError> {
+    match redis.send(Command(command)).await {
+        Ok(Ok(RespValue::Error(err))) => Err(AppError::Redis(err)),
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(err)) => Err(AppError::Redis(err.to_string())),
+        Err(err) => Err(AppError::Redis(err.to_string())),
+    }
+}
+
+/// Payload of a bulk or simple string reply, `None` for any other kind of reply
+pub fn bulk_string(value: RespValue) -> Option<String> {
+    match value {
+        RespValue::BulkString(bytes) => String::from_utf8(bytes).ok(),
+        RespValue::SimpleString(string) => Some(string),
+        _ => None,
+    }
+}
+
+/// Value of an integer reply, `None` for any other kind of reply
+pub fn integer(value: &RespValue) -> Option<i64> {
+    match value {
+        RespValue::Integer(n) => Some(*n),
+        _ => None,
+    }
+}
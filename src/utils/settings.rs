@@ -0,0 +1,544 @@
+use serde::de::DeserializeOwned;
+use serde_derive::Serialize;
+use std::env::VarError;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::Path;
+use toml::{Table, Value};
+
+use crate::utils::config::{
+    AuthConfig, CookieSameSite, DatabaseConfig, JwtConfig, LoginThrottleConfig, MailConfig,
+    OidcConfig, PasswordConfig, RateLimitConfig, RedisConfig, SecurityConfig, ServerConfig,
+    SessionConfig, TwoFactorConfig, UNIX_SOCKET_PREFIX,
+};
+
+/// Prefix of the environment variables overriding the files, e.g. `APP_DB__URL`
+const ENV_PREFIX: &str = "APP_";
+
+/// Separates the section from the key, and nested tables, in environment variable names
+const ENV_SEPARATOR: &str = "__";
+
+/// Selects the `settings.{env}.toml` file layered over `settings.toml`
+const ENV_NAME_VARIABLE: &str = "APP_ENV";
+
+/// Shortest secret accepted, `Key::derive_from` refuses anything under 32 bytes
+const MIN_SECRET_LENGTH: usize = 32;
+
+/// Start of the placeholder secrets of older copies of `settings.example.toml`
+const PLACEHOLDER_SECRET_PREFIX: &str = "change-me";
+
+/// Everything wrong with the configuration, reported at once rather than one problem per start
+#[derive(Debug)]
+pub struct SettingsError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+/// The whole configuration, one field per section of `settings.toml`
+///
+/// Layered from lowest to highest precedence:
+///
+/// 1. the defaults of each section
+/// 2. `settings.toml`
+/// 3. `settings.{env}.toml`, where `env` is the value of `APP_ENV`
+/// 4. `APP_{SECTION}__{KEY}` environment variables, e.g. `APP_REDIS__ADDRESS` or
+///    `APP_OIDC__PROVIDERS__GOOGLE__CLIENT_SECRET`
+///
+/// Both files are optional, so a container can be configured through the environment alone.
+#[derive(Clone, Default, Serialize)]
+pub struct Settings {
+    pub server: ServerConfig,
+    pub db: DatabaseConfig,
+    pub redis: RedisConfig,
+    pub session: SessionConfig,
+    pub security: SecurityConfig,
+    pub auth: AuthConfig,
+    pub password: PasswordConfig,
+    pub login_throttle: LoginThrottleConfig,
+    pub jwt: JwtConfig,
+    pub two_factor: TwoFactorConfig,
+    pub oidc: OidcConfig,
+    pub rate_limit: RateLimitConfig,
+    pub mail: MailConfig,
+}
+
+impl Settings {
+    /// Load and validate the configuration, `path` being the base file, usually `settings.toml`
+    pub fn load(path: &str) -> Result<Settings, SettingsError> {
+        let mut problems = Vec::new();
+        let env_name = match std::env::var(ENV_NAME_VARIABLE) {
+            Ok(env_name) => Some(env_name),
+            Err(VarError::NotPresent) => None,
+            Err(VarError::NotUnicode(_)) => {
+                problems.push(format!("{}: not valid UTF-8", ENV_NAME_VARIABLE));
+                None
+            }
+        };
+
+        Settings::from_layers(
+            Path::new(path),
+            env_name.as_deref(),
+            std::env::vars_os(),
+            problems,
+        )
+    }
+
+    /// Layer the files and `vars` over the defaults and validate the result
+    fn from_layers(
+        path: &Path,
+        env_name: Option<&str>,
+        vars: impl Iterator<Item = (OsString, OsString)>,
+        mut problems: Vec<String>,
+    ) -> Result<Settings, SettingsError> {
+        // The defaults are a layer of their own so environment variables know each key's type
+        let mut merged =
+            Table::try_from(Settings::default()).expect("default settings serialize to TOML");
+
+        merge_file(&mut merged, path, &mut problems);
+        if let Some(env_name) = env_name {
+            let env_path = path.with_extension(format!("{}.toml", env_name));
+            merge_file(&mut merged, &env_path, &mut problems);
+        }
+        merge_env(&mut merged, vars, &mut problems);
+
+        let settings = Settings {
+            server: section(&mut merged, "server", &mut problems),
+            db: section(&mut merged, "db", &mut problems),
+            redis: section(&mut merged, "redis", &mut problems),
+            session: section(&mut merged, "session", &mut problems),
+            security: section(&mut merged, "security", &mut problems),
+            auth: section(&mut merged, "auth", &mut problems),
+            password: section(&mut merged, "password", &mut problems),
+            login_throttle: section(&mut merged, "login_throttle", &mut problems),
+            jwt: section(&mut merged, "jwt", &mut problems),
+            two_factor: section(&mut merged, "two_factor", &mut problems),
+            oidc: section(&mut merged, "oidc", &mut problems),
+            rate_limit: section(&mut merged, "rate_limit", &mut problems),
+            mail: section(&mut merged, "mail", &mut problems),
+        };
+        // Every known section has been taken out, anything left is a typo
+        for name in merged.keys() {
+            problems.push(format!("[{}]: unknown section", name));
+        }
+        settings.validate(&mut problems);
+
+        if problems.is_empty() {
+            Ok(settings)
+        } else {
+            Err(SettingsError { problems })
+        }
+    }
+
+    /// Checks the values serde cannot, adding a message per problem found
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.server.listen.is_empty() {
+            problems.push("server.listen: at least one address is required".to_string());
+        }
+        for address in &self.server.listen {
+            if address.strip_prefix(UNIX_SOCKET_PREFIX) == Some("") {
+                problems.push("server.listen: empty Unix socket path".to_string());
+            } else if !address.starts_with(UNIX_SOCKET_PREFIX) && !address.contains(':') {
+                problems.push(format!(
+                    "server.listen: `{}` is neither host:port nor unix:/path",
+                    address
+                ));
+            }
+        }
+        if self.server.workers == Some(0) {
+            problems.push("server.workers: must be at least 1".to_string());
+        }
+        if let Some(tls) = &self.server.tls {
+            for (name, path) in [("cert_path", &tls.cert_path), ("key_path", &tls.key_path)] {
+                if !Path::new(path).is_file() {
+                    problems.push(format!("server.tls.{}: `{}` is not a file", name, path));
+                }
+            }
+        }
+        if self.db.url.is_empty() {
+            problems.push("db.url: required".to_string());
+        }
+        if self.db.max_connections == 0 {
+            problems.push("db.max_connections: must be at least 1".to_string());
+        }
+        if self.redis.address.is_empty() {
+            problems.push("redis.address: required".to_string());
+        }
+        if self.session.ttl_days <= 0 {
+            problems.push("session.ttl_days: must be at least 1".to_string());
+        }
+        if self.session.cookie_name.is_empty() {
+            problems.push("session.cookie_name: required".to_string());
+        }
+        if self.session.cookie_same_site == CookieSameSite::None && !self.session.cookie_secure {
+            problems
+                .push("session.cookie_same_site: \"none\" needs cookie_secure = true".to_string());
+        }
+
+        for (name, secret) in [
+            ("key_secret", &self.security.key_secret),
+            ("jwt_secret", &self.security.jwt_secret),
+        ] {
+            if secret.len() < MIN_SECRET_LENGTH {
+                problems.push(format!(
+                    "security.{}: must be at least {} bytes long",
+                    name, MIN_SECRET_LENGTH
+                ));
+            } else if secret.starts_with(PLACEHOLDER_SECRET_PREFIX) {
+                problems.push(format!(
+                    "security.{}: replace the example placeholder with a random string",
+                    name
+                ));
+            }
+        }
+        if !self.security.key_secret.is_empty()
+            && self.security.key_secret == self.security.jwt_secret
+        {
+            problems.push("security.jwt_secret: must differ from key_secret".to_string());
+        }
+
+        if !(4..=31).contains(&self.password.bcrypt_cost) {
+            problems.push("password.bcrypt_cost: must be between 4 and 31".to_string());
+        }
+        if let Err(err) = argon2::Params::new(
+            self.password.argon2_memory_kib,
+            self.password.argon2_iterations,
+            self.password.argon2_parallelism,
+            None,
+        ) {
+            problems.push(format!("password: invalid argon2 parameters: {}", err));
+        }
+
+        if self.two_factor.issuer.is_empty() || self.two_factor.issuer.contains(':') {
+            problems.push("two_factor.issuer: must be non-empty and without ':'".to_string());
+        }
+        if self.jwt.access_token_ttl_seconds <= 0 || self.jwt.refresh_token_ttl_days <= 0 {
+            problems.push("jwt: token lifetimes must be positive".to_string());
+        }
+
+        for (index, rule) in self.rate_limit.rules.iter().enumerate() {
+            if rule.limit == 0 || rule.period_seconds == 0 {
+                problems.push(format!(
+                    "rate_limit.rules[{}] ({}): limit and period_seconds must be positive",
+                    index, rule.pattern
+                ));
+            }
+        }
+
+        for (name, provider) in &self.oidc.providers {
+            for (field, value) in [
+                ("issuer", &provider.issuer),
+                ("client_id", &provider.client_id),
+                ("authorization_endpoint", &provider.authorization_endpoint),
+                ("token_endpoint", &provider.token_endpoint),
+                ("userinfo_endpoint", &provider.userinfo_endpoint),
+                ("redirect_uri", &provider.redirect_uri),
+            ] {
+                if value.is_empty() {
+                    problems.push(format!("oidc.providers.{}.{}: required", name, field));
+                }
+            }
+        }
+
+        if self.mail.from.is_empty() {
+            problems.push("mail.from: required".to_string());
+        }
+    }
+}
+
+/// Deserialize the `name` section, falling back to defaults when it is missing or invalid
+/// so the remaining sections still get checked
+fn section<T: DeserializeOwned + Default>(
+    merged: &mut Table,
+    name: &str,
+    problems: &mut Vec<String>,
+) -> T {
+    match merged.remove(name) {
+        Some(value) => value.try_into().unwrap_or_else(|err: toml::de::Error| {
+            problems.push(format!(
+                "[{}]: {}",
+                name,
+                err.to_string().trim().replace('\n', " ")
+            ));
+            T::default()
+        }),
+        None => T::default(),
+    }
+}
+
+/// Layer a TOML file over `merged`, doing nothing if it does not exist
+fn merge_file(merged: &mut Table, path: &Path, problems: &mut Vec<String>) {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return,
+        Err(err) => {
+            problems.push(format!("{}: {}", path.display(), err));
+            return;
+        }
+    };
+
+    match content.parse::<Table>() {
+        Ok(table) => merge_table(merged, table),
+        Err(err) => problems.push(format!("{}: {}", path.display(), err)),
+    }
+}
+
+/// Recursively merge `overlay` into `base`, tables key by key and any other value as a whole
+fn merge_table(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(overlay_table)) => {
+                merge_table(base_table, overlay_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Layer `APP_{SECTION}__{KEY}` variables over `merged`
+///
+/// Values are read as TOML, so `8080`, `true` or `["a", "b"]` get their type, and anything
+/// that does not parse is taken as a string. A key that already holds a string, which is
+/// every string setting thanks to the defaults layer, stays a string so that e.g. a numeric
+/// password cannot turn into an integer.
+fn merge_env(
+    merged: &mut Table,
+    vars: impl Iterator<Item = (OsString, OsString)>,
+    problems: &mut Vec<String>,
+) {
+    for (name, raw) in vars {
+        // Other programs' variables may hold anything, only ours have to be UTF-8
+        if !name.to_string_lossy().starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let (Some(name), Some(raw)) = (name.to_str(), raw.to_str()) else {
+            problems.push(format!("{}: not valid UTF-8", name.to_string_lossy()));
+            continue;
+        };
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        // Variables without a separator, such as `APP_ENV`, are not settings
+        if !path.contains(ENV_SEPARATOR) {
+            continue;
+        }
+        let keys: Vec<String> = path.split(ENV_SEPARATOR).map(str::to_lowercase).collect();
+        if keys.iter().any(String::is_empty) {
+            problems.push(format!("{}: empty key in variable name", name));
+            continue;
+        }
+
+        if let Err(reason) = set_env_value(merged, &keys, raw) {
+            problems.push(format!("{}: {}", name, reason));
+        }
+    }
+}
+
+/// Set the value at `keys`, creating the tables on the way
+fn set_env_value(table: &mut Table, keys: &[String], raw: &str) -> Result<(), String> {
+    let (key, rest) = keys.split_first().ok_or("empty key in variable name")?;
+    if rest.is_empty() {
+        let value = parse_env_value(raw, table.get(key.as_str()));
+        table.insert(key.clone(), value);
+        return Ok(());
+    }
+
+    match table
+        .entry(key.as_str())
+        .or_insert_with(|| Value::Table(Table::new()))
+    {
+        Value::Table(child) => set_env_value(child, rest, raw),
+        _ => Err(format!("`{}` is not a table", key)),
+    }
+}
+
+fn parse_env_value(raw: &str, current: Option<&Value>) -> Value {
+    if let Some(Value::String(_)) = current {
+        return Value::String(raw.to_string());
+    }
+
+    format!("value = {}", raw)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+    use std::path::PathBuf;
+
+    const KEY_SECRET: &str = "0123456789abcdef0123456789abcdef";
+    const JWT_SECRET: &str = "fedcba9876543210fedcba9876543210";
+
+    fn vars(pairs: &[(&str, &str)]) -> std::vec::IntoIter<(OsString, OsString)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (OsString::from(name), OsString::from(value)))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Writes `files` to a directory of their own and returns the path of `settings.toml`
+    fn settings_dir(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("settings-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        dir.join("settings.toml")
+    }
+
+    #[test]
+    fn environment_file_and_variables_override_the_base_file() {
+        let path = settings_dir(
+            "layers",
+            &[
+                (
+                    "settings.toml",
+                    "[server]\nworkers = 2\n[db]\nurl = \"postgres://base\"\nmax_connections = 3\n",
+                ),
+                ("settings.test.toml", "[db]\nurl = \"postgres://test\"\n"),
+            ],
+        );
+
+        let settings = Settings::from_layers(
+            &path,
+            Some("test"),
+            vars(&[
+                ("APP_DB__MAX_CONNECTIONS", "7"),
+                ("APP_SECURITY__KEY_SECRET", KEY_SECRET),
+                ("APP_SECURITY__JWT_SECRET", JWT_SECRET),
+                ("APP_ENV", "test"),
+                ("HOME", "/root"),
+            ]),
+            Vec::new(),
+        )
+        .unwrap();
+
+        assert_eq!(settings.server.workers, Some(2));
+        assert_eq!(settings.db.url, "postgres://test");
+        assert_eq!(settings.db.max_connections, 7);
+        assert_eq!(settings.security.key_secret, KEY_SECRET);
+        assert_eq!(settings.redis.address, "127.0.0.1:6379");
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn numeric_variables_stay_strings_for_string_settings() {
+        let settings = Settings::from_layers(
+            Path::new("missing-settings.toml"),
+            None,
+            vars(&[
+                ("APP_DB__URL", "postgres://env"),
+                (
+                    "APP_SECURITY__KEY_SECRET",
+                    "12345678901234567890123456789012",
+                ),
+                ("APP_SECURITY__JWT_SECRET", JWT_SECRET),
+                (
+                    "APP_SERVER__LISTEN",
+                    "[\"0.0.0.0:80\", \"unix:/run/app.sock\"]",
+                ),
+            ]),
+            Vec::new(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            settings.security.key_secret,
+            "12345678901234567890123456789012"
+        );
+        assert_eq!(
+            settings.server.listen,
+            vec!["0.0.0.0:80".to_string(), "unix:/run/app.sock".to_string()]
+        );
+    }
+
+    #[test]
+    fn invalid_variables_and_values_are_all_reported() {
+        let mut pairs: Vec<_> = vars(&[
+            ("APP_DB__URL", "postgres://env"),
+            ("APP_DB__MAX_CONNECTIONS", "\"many\""),
+            ("APP_NOPE__KEY", "1"),
+            (
+                "APP_SECURITY__KEY_SECRET",
+                "change-me-to-a-long-random-string-please",
+            ),
+            ("APP_SECURITY__JWT_SECRET", "short"),
+        ])
+        .collect();
+        pairs.push((
+            OsString::from("APP_MAIL__FROM"),
+            OsString::from_vec(b"no-reply@\xffexample.com".to_vec()),
+        ));
+        pairs.push((
+            OsString::from_vec(b"OTHER_\xff".to_vec()),
+            OsString::from("1"),
+        ));
+
+        let problems = Settings::from_layers(
+            Path::new("missing-settings.toml"),
+            None,
+            pairs.into_iter(),
+            Vec::new(),
+        )
+        .err()
+        .unwrap()
+        .problems;
+
+        assert!(problems.contains(&"APP_MAIL__FROM: not valid UTF-8".to_string()));
+        assert!(problems.iter().any(|problem| problem.starts_with("[db]:")));
+        // The invalid section falls back to its defaults, which lack the url
+        assert!(problems.contains(&"db.url: required".to_string()));
+        assert!(problems.contains(&"[nope]: unknown section".to_string()));
+        assert!(problems.contains(
+            &"security.key_secret: replace the example placeholder with a random string"
+                .to_string()
+        ));
+        assert!(
+            problems.contains(&"security.jwt_secret: must be at least 32 bytes long".to_string())
+        );
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+    }
+
+    #[test]
+    fn example_settings_need_real_secrets() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("settings.example.toml");
+
+        let problems = Settings::from_layers(
+            &path,
+            None,
+            vars(&[("APP_DB__URL", "postgres://env")]),
+            Vec::new(),
+        )
+        .err()
+        .unwrap()
+        .problems;
+
+        assert_eq!(
+            problems,
+            vec![
+                "security.key_secret: must be at least 32 bytes long".to_string(),
+                "security.jwt_secret: must be at least 32 bytes long".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,41 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+
+use crate::utils::config::TlsConfig;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Build the rustls server configuration from the PEM files of `[server.tls]`
+pub fn load_rustls_config(config: &TlsConfig) -> io::Result<ServerConfig> {
+    let mut cert_file = BufReader::new(File::open(&config.cert_path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut cert_file)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "No certificate found in {}",
+            config.cert_path
+        )));
+    }
+
+    let mut key_file = BufReader::new(File::open(&config.key_path)?);
+    let key = rustls_pemfile::read_all(&mut key_file)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data(format!("No private key found in {}", config.key_path)))?;
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid_data(format!("Invalid TLS certificate or key: {}", err)))
+}
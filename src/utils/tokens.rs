@@ -0,0 +1,43 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues random one-time tokens and signs them with the server secret
+///
+/// Only the signature is persisted, so a leaked token table cannot be replayed
+/// without also knowing `security.key_secret`.
+#[derive(Clone)]
+pub struct TokenSigner {
+    key: Vec<u8>,
+}
+
+impl TokenSigner {
+    pub fn new(secret: &str) -> Self {
+        TokenSigner {
+            key: secret.as_bytes().to_vec(),
+        }
+    }
+
+    /// Generate a new token
+    ///
+    /// # Returns
+    ///
+    /// A tuple of the URL-safe token to hand to the user and the signature to store
+    pub fn generate(&self) -> (String, String) {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+        let signature = self.sign(&token);
+        (token, signature)
+    }
+
+    /// Hex-encoded HMAC-SHA256 of `token`, used to look the token up in the database
+    pub fn sign(&self, token: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(token.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+}